@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE queued_posts;
+DROP TABLE digest_settings;
+ALTER TABLE subscribed_listings
+    DROP COLUMN delivery_mode,
+    DROP COLUMN digest_size,
+    DROP COLUMN last_digest_at;
@@ -0,0 +1,21 @@
+-- Your SQL goes here
+ALTER TABLE subscribed_listings
+    ADD COLUMN delivery_mode TEXT NOT NULL DEFAULT 'realtime',
+    ADD COLUMN digest_size INT,
+    ADD COLUMN last_digest_at TIMESTAMPTZ;
+
+CREATE TABLE digest_settings (
+    chat_id BIGINT PRIMARY KEY,
+    timezone TEXT NOT NULL DEFAULT 'UTC',
+    digest_hour SMALLINT NOT NULL DEFAULT 9
+);
+
+CREATE TABLE queued_posts (
+    chat_id BIGINT NOT NULL,
+    subreddit TEXT NOT NULL,
+    category TEXT NOT NULL,
+    post_id TEXT NOT NULL REFERENCES artposts(id),
+    score INT DEFAULT 0 NOT NULL,
+    queued_at TIMESTAMPTZ DEFAULT NOW() NOT NULL,
+    PRIMARY KEY (chat_id, post_id)
+)
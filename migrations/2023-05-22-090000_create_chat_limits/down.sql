@@ -0,0 +1,8 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE chat_settings
+    DROP COLUMN quiet_start,
+    DROP COLUMN quiet_end,
+    DROP COLUMN hourly_budget,
+    DROP COLUMN overflow_policy;
+
+ALTER TABLE chat_settings RENAME TO digest_settings;
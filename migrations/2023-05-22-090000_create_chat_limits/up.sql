@@ -0,0 +1,8 @@
+-- Your SQL goes here
+ALTER TABLE digest_settings RENAME TO chat_settings;
+
+ALTER TABLE chat_settings
+    ADD COLUMN quiet_start SMALLINT,
+    ADD COLUMN quiet_end SMALLINT,
+    ADD COLUMN hourly_budget INT,
+    ADD COLUMN overflow_policy TEXT NOT NULL DEFAULT 'queue';
@@ -0,0 +1,21 @@
+-- Your SQL goes here
+CREATE TABLE outbound_posts (
+    chat_id BIGINT NOT NULL,
+    post_id TEXT NOT NULL REFERENCES artposts(id),
+    attempts INT DEFAULT 0 NOT NULL,
+    next_attempt_at TIMESTAMPTZ DEFAULT NOW() NOT NULL,
+    last_error TEXT,
+    queued_at TIMESTAMPTZ DEFAULT NOW() NOT NULL,
+    PRIMARY KEY (chat_id, post_id)
+);
+
+CREATE INDEX outbound_posts_next_attempt_at ON outbound_posts (next_attempt_at);
+
+CREATE TABLE dead_letters (
+    chat_id BIGINT NOT NULL,
+    post_id TEXT NOT NULL REFERENCES artposts(id),
+    attempts INT NOT NULL,
+    error TEXT NOT NULL,
+    failed_at TIMESTAMPTZ DEFAULT NOW() NOT NULL,
+    PRIMARY KEY (chat_id, post_id)
+)
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+CREATE TABLE media_files (
+    media_href TEXT PRIMARY KEY,
+    file_id TEXT NOT NULL,
+    cached_at TIMESTAMPTZ DEFAULT NOW() NOT NULL
+)
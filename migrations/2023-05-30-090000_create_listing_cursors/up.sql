@@ -0,0 +1,9 @@
+-- Your SQL goes here
+CREATE TABLE listing_cursors (
+    source TEXT NOT NULL,
+    subreddit TEXT NOT NULL,
+    category TEXT NOT NULL,
+    anchor TEXT NOT NULL,
+    updated_at TIMESTAMPTZ DEFAULT NOW() NOT NULL,
+    PRIMARY KEY (source, subreddit, category)
+)
@@ -0,0 +1,11 @@
+-- Your SQL goes here
+CREATE TABLE seen_posts (
+    source TEXT NOT NULL,
+    subreddit TEXT NOT NULL,
+    category TEXT NOT NULL,
+    post_id TEXT NOT NULL,
+    seen_at TIMESTAMPTZ DEFAULT NOW() NOT NULL,
+    PRIMARY KEY (source, subreddit, category, post_id)
+);
+
+CREATE INDEX seen_posts_seen_at ON seen_posts (source, subreddit, category, seen_at);
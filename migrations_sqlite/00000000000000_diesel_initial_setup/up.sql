@@ -0,0 +1,2 @@
+-- Keeps the versions in step with `migrations/`. SQLite connections get
+-- diesel's helper functions without a migration.
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+CREATE TABLE artposts (
+    id TEXT PRIMARY KEY NOT NULL,
+    media_href TEXT NOT NULL,
+    title TEXT NOT NULL,
+    author TEXT NOT NULL,
+    ups INTEGER DEFAULT 0 NOT NULL,
+    downs INTEGER DEFAULT 0 NOT NULL
+)
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+CREATE TABLE botclients (
+    id BIGINT PRIMARY KEY NOT NULL,
+    username TEXT,
+    is_user BOOLEAN NOT NULL
+)
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE queued_posts;
+DROP TABLE digest_settings;
+ALTER TABLE subscribed_listings DROP COLUMN delivery_mode;
+ALTER TABLE subscribed_listings DROP COLUMN digest_size;
+ALTER TABLE subscribed_listings DROP COLUMN last_digest_at;
@@ -0,0 +1,20 @@
+-- Your SQL goes here
+ALTER TABLE subscribed_listings ADD COLUMN delivery_mode TEXT NOT NULL DEFAULT 'realtime';
+ALTER TABLE subscribed_listings ADD COLUMN digest_size INTEGER;
+ALTER TABLE subscribed_listings ADD COLUMN last_digest_at TEXT;
+
+CREATE TABLE digest_settings (
+    chat_id BIGINT PRIMARY KEY NOT NULL,
+    timezone TEXT NOT NULL DEFAULT 'UTC',
+    digest_hour SMALLINT NOT NULL DEFAULT 9
+);
+
+CREATE TABLE queued_posts (
+    chat_id BIGINT NOT NULL,
+    subreddit TEXT NOT NULL,
+    category TEXT NOT NULL,
+    post_id TEXT NOT NULL REFERENCES artposts(id),
+    score INTEGER DEFAULT 0 NOT NULL,
+    queued_at TEXT DEFAULT (strftime('%Y-%m-%d %H:%M:%f+00:00', 'now')) NOT NULL,
+    PRIMARY KEY (chat_id, post_id)
+)
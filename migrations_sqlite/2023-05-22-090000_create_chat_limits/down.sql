@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE chat_settings DROP COLUMN quiet_start;
+ALTER TABLE chat_settings DROP COLUMN quiet_end;
+ALTER TABLE chat_settings DROP COLUMN hourly_budget;
+ALTER TABLE chat_settings DROP COLUMN overflow_policy;
+
+ALTER TABLE chat_settings RENAME TO digest_settings;
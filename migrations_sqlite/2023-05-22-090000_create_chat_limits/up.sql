@@ -0,0 +1,7 @@
+-- Your SQL goes here
+ALTER TABLE digest_settings RENAME TO chat_settings;
+
+ALTER TABLE chat_settings ADD COLUMN quiet_start SMALLINT;
+ALTER TABLE chat_settings ADD COLUMN quiet_end SMALLINT;
+ALTER TABLE chat_settings ADD COLUMN hourly_budget INTEGER;
+ALTER TABLE chat_settings ADD COLUMN overflow_policy TEXT NOT NULL DEFAULT 'queue';
@@ -0,0 +1,21 @@
+-- Your SQL goes here
+CREATE TABLE outbound_posts (
+    chat_id BIGINT NOT NULL,
+    post_id TEXT NOT NULL REFERENCES artposts(id),
+    attempts INTEGER DEFAULT 0 NOT NULL,
+    next_attempt_at TEXT DEFAULT (strftime('%Y-%m-%d %H:%M:%f+00:00', 'now')) NOT NULL,
+    last_error TEXT,
+    queued_at TEXT DEFAULT (strftime('%Y-%m-%d %H:%M:%f+00:00', 'now')) NOT NULL,
+    PRIMARY KEY (chat_id, post_id)
+);
+
+CREATE INDEX outbound_posts_next_attempt_at ON outbound_posts (next_attempt_at);
+
+CREATE TABLE dead_letters (
+    chat_id BIGINT NOT NULL,
+    post_id TEXT NOT NULL REFERENCES artposts(id),
+    attempts INTEGER NOT NULL,
+    error TEXT NOT NULL,
+    failed_at TEXT DEFAULT (strftime('%Y-%m-%d %H:%M:%f+00:00', 'now')) NOT NULL,
+    PRIMARY KEY (chat_id, post_id)
+)
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+CREATE TABLE media_files (
+    media_href TEXT PRIMARY KEY NOT NULL,
+    file_id TEXT NOT NULL,
+    cached_at TEXT DEFAULT (strftime('%Y-%m-%d %H:%M:%f+00:00', 'now')) NOT NULL
+)
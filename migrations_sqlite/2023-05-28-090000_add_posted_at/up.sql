@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE artposts ADD COLUMN posted_at TEXT;
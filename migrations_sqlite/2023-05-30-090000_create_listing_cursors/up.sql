@@ -0,0 +1,9 @@
+-- Your SQL goes here
+CREATE TABLE listing_cursors (
+    source TEXT NOT NULL,
+    subreddit TEXT NOT NULL,
+    category TEXT NOT NULL,
+    anchor TEXT NOT NULL,
+    updated_at TEXT DEFAULT (strftime('%Y-%m-%d %H:%M:%f+00:00', 'now')) NOT NULL,
+    PRIMARY KEY (source, subreddit, category)
+)
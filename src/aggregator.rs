@@ -1,17 +1,17 @@
-use std::collections::VecDeque;
-use std::env;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
-use diesel::pg::PgConnection;
 use diesel::prelude::*;
-use dotenvy::dotenv;
-use tokio::sync::mpsc::{channel, Receiver, Sender};
-use tokio::sync::Mutex;
+use futures::future::select_all;
+use log::warn;
+use tokio_util::sync::CancellationToken;
 
 use crate::auth::ClientID;
-use crate::content::Post;
-use crate::curator::Curator;
-use crate::listings::reddit::{Api, Listing};
+use crate::content::{Post, SubscribedListing};
+use crate::curator::{Curator, FeedEvent, Subscription};
+use crate::db::{with_conn, DbPool};
+use crate::delivery::DeliveryMode;
+use crate::listings::reddit::{Listing, Subreddit};
 use crate::listings::source::ListingSource;
 
 pub trait Filter {
@@ -43,25 +43,18 @@ impl Filter for SimilarFilter {
 }
 
 pub struct UserAggregator<SRC> {
-    id: ClientID,
     pub curator: Option<Curator<SRC>>,
-    listings: VecDeque<Listing>,
-    cache: VecDeque<Post>,
-    pub chan: (Sender<Post>, Receiver<Post>),
+    feeds: Vec<Subscription>,
 }
 
 impl<SRC> UserAggregator<SRC>
 where
     SRC: ListingSource + Send + Sync + 'static,
 {
-    fn new(id: ClientID) -> Self {
-        let (tx, rcv) = channel(10);
+    fn new() -> Self {
         UserAggregator {
-            id,
-            listings: Default::default(),
-            cache: VecDeque::with_capacity(5),
-            chan: (tx, rcv),
             curator: None,
+            feeds: vec![],
         }
     }
 
@@ -69,53 +62,207 @@ where
         if self.curator.is_none() {
             panic!("must attach a Curator to an UserAggregator")
         }
-        let listing = Arc::new(Mutex::new(category));
-        self.curator.as_mut().unwrap().spawn_for(listing);
+        let feed = self.curator.as_ref().unwrap().subscribe(category);
+        self.feeds.push(feed);
+    }
+
+    /// Waits for the next event from any of the subscribed listings.
+    pub async fn recv(&mut self) -> Option<FeedEvent> {
+        if self.feeds.is_empty() {
+            return None;
+        }
+        let feeds = self.feeds.iter_mut().map(|feed| Box::pin(feed.recv()));
+        let (event, _, _) = select_all(feeds).await;
+        event
     }
 
     pub fn attach_curator(&mut self, curator: Curator<SRC>) {
         self.curator = Some(curator);
     }
+
+    /// An aggregator that doesn't follow any listing yet.
+    pub fn create(curator: &Curator<SRC>) -> Self {
+        let mut aggregator = UserAggregator::new();
+        aggregator.attach_curator(curator.clone());
+        aggregator
+    }
 }
 
-pub struct AggregatorStore {
-    db: PgConnection,
+/// Listings are keyed like the `subscribed_listings` table.
+type ListenerKey = (i64, Subreddit, String);
+
+/// The `/listen` tasks delivering each chat's listings, so `/silence` can
+/// stop them. Clones share the same tasks.
+#[derive(Clone, Default)]
+pub struct Listeners {
+    tasks: Arc<Mutex<HashMap<ListenerKey, CancellationToken>>>,
 }
 
-impl AggregatorStore {
-    pub fn instance() -> Self {
-        Self {
-            db: Self::db_instance(),
+impl Listeners {
+    fn key(client: ClientID, listing: &Listing) -> ListenerKey {
+        (client.id(), listing.subreddit(), listing.category())
+    }
+
+    /// Registers a task delivering `listing` to `client`, returning what
+    /// cancels it, or `None` when one already does.
+    pub fn start(&self, client: ClientID, listing: &Listing) -> Option<CancellationToken> {
+        let mut tasks = self.tasks.lock().unwrap();
+        let key = Listeners::key(client, listing);
+        if tasks.get(&key).is_some_and(|task| !task.is_cancelled()) {
+            return None;
         }
+        let cancel = CancellationToken::new();
+        tasks.insert(key, cancel.clone());
+        Some(cancel)
     }
 
-    fn db_instance() -> PgConnection {
-        dotenv().ok();
-        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-        PgConnection::establish(&database_url)
-            .unwrap_or_else(|_| panic!("Error connecting to {}", database_url))
+    /// Forgets the task of a listing that stopped on its own.
+    pub fn finish(&self, client: ClientID, listing: &Listing) {
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.remove(&Listeners::key(client, listing));
     }
 
-    pub fn find(&mut self, client: ClientID) -> Option<UserAggregator<Api>> {
-        use crate::content::*;
+    /// Stops every task delivering a listing of `sub` to `client`.
+    pub fn stop(&self, client: ClientID, sub: &Subreddit) {
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.retain(|(user, target, _), cancel| {
+            if *user != client.id() || target != sub {
+                return true;
+            }
+            cancel.cancel();
+            false
+        });
+    }
+}
+
+/// Remembers which listings each chat subscribed to, and how it wants their
+/// posts delivered.
+pub trait AggregatorStore: Send {
+    /// The listings `client` subscribed to.
+    fn listings(&mut self, client: ClientID) -> QueryResult<Vec<Listing>>;
+
+    /// Every chat's subscriptions, to resume listening to after a restart.
+    fn all(&mut self) -> QueryResult<Vec<(ClientID, Listing)>>;
+
+    fn subscribe(&mut self, client: ClientID, listing: &Listing) -> QueryResult<usize>;
+
+    fn unsubscribe(&mut self, client: ClientID, listing: &Listing) -> QueryResult<usize>;
+
+    /// Changes how posts of an existing subscription are delivered. Returns
+    /// `false` when `client` isn't subscribed to `listing`.
+    fn set_delivery_mode(
+        &mut self,
+        client: ClientID,
+        listing: &Listing,
+        mode: DeliveryMode,
+    ) -> QueryResult<bool>;
+
+    fn delivery_mode(&mut self, client: ClientID, listing: &Listing) -> DeliveryMode;
+}
+
+/// The `AggregatorStore` kept in the bot's database, Postgres or SQLite.
+pub struct SqlAggregatorStore {
+    db: DbPool,
+}
+
+impl SqlAggregatorStore {
+    pub fn from(pool: &DbPool) -> Self {
+        Self { db: pool.clone() }
+    }
+}
+
+impl SqlAggregatorStore {
+    fn parse(listing: &SubscribedListing) -> Option<Listing> {
+        let parsed = Listing::parse(listing.category.as_str(), listing.subreddit.as_str().into());
+        if parsed.is_none() {
+            warn!(
+                "Skipping unknown listing `{}/{}`",
+                listing.subreddit, listing.category
+            );
+        }
+        parsed
+    }
+}
+
+impl AggregatorStore for SqlAggregatorStore {
+    fn listings(&mut self, client: ClientID) -> QueryResult<Vec<Listing>> {
         use crate::schema::subscribed_listings::dsl::*;
 
-        let listings = subscribed_listings
+        let listings = with_conn!(self.db, |conn| subscribed_listings
             .filter(user_id.eq(client.id()))
-            .load::<SubscribedListing>(&mut self.db)
-            .expect("error loading subscribed listings.");
-
-        let mut aggregator: UserAggregator<Api> = UserAggregator::new(client);
-        aggregator.attach_curator(Curator::from(Api::from(&reqwest::Client::new())));
-        for listing in listings {
-            let listing = Listing::from(listing.category.as_str(), listing.subreddit.into());
-            aggregator.add_listing(listing);
-        }
+            .load::<SubscribedListing>(conn))?;
 
-        Some(aggregator)
+        Ok(listings.iter().filter_map(Self::parse).collect())
     }
 
-    pub fn create(&mut self, client_id: ClientID) -> &mut UserAggregator<Api> {
-        todo!()
+    fn all(&mut self) -> QueryResult<Vec<(ClientID, Listing)>> {
+        use crate::schema::subscribed_listings::dsl::*;
+
+        let listings = with_conn!(self.db, |conn| subscribed_listings
+            .load::<SubscribedListing>(conn))?;
+
+        Ok(listings
+            .iter()
+            .filter_map(|listing| Some((listing.user_id.into(), Self::parse(listing)?)))
+            .collect())
+    }
+
+    fn subscribe(&mut self, client: ClientID, listing: &Listing) -> QueryResult<usize> {
+        use crate::schema::subscribed_listings::dsl::*;
+
+        with_conn!(self.db, |conn| diesel::insert_into(subscribed_listings)
+            .values((
+                user_id.eq(client.id()),
+                subreddit.eq(listing.subreddit().name()),
+                category.eq(listing.category()),
+            ))
+            .on_conflict_do_nothing()
+            .execute(conn))
+    }
+
+    fn unsubscribe(&mut self, client: ClientID, listing: &Listing) -> QueryResult<usize> {
+        use crate::schema::subscribed_listings::dsl::*;
+
+        with_conn!(self.db, |conn| diesel::delete(subscribed_listings.find((
+            client.id(),
+            listing.subreddit().name(),
+            listing.category(),
+        )))
+        .execute(conn))
+    }
+
+    fn set_delivery_mode(
+        &mut self,
+        client: ClientID,
+        listing: &Listing,
+        mode: DeliveryMode,
+    ) -> QueryResult<bool> {
+        use crate::schema::subscribed_listings::dsl::*;
+
+        let updated = with_conn!(self.db, |conn| diesel::update(subscribed_listings.find((
+            client.id(),
+            listing.subreddit().name(),
+            listing.category(),
+        )))
+        .set((
+            delivery_mode.eq(mode.tag()),
+            digest_size.eq(mode.digest_size()),
+        ))
+        .execute(conn))?;
+        Ok(updated > 0)
+    }
+
+    fn delivery_mode(&mut self, client: ClientID, listing: &Listing) -> DeliveryMode {
+        use crate::content::*;
+        use crate::schema::subscribed_listings::dsl::*;
+
+        let subscription = with_conn!(self.db, |conn| subscribed_listings
+            .find((client.id(), listing.subreddit().name(), listing.category()))
+            .get_result::<SubscribedListing>(conn));
+        match subscription {
+            Ok(sub) => DeliveryMode::from(sub.delivery_mode.as_str(), sub.digest_size)
+                .unwrap_or(DeliveryMode::Realtime),
+            Err(_) => DeliveryMode::Realtime,
+        }
     }
 }
@@ -1,27 +1,42 @@
-use std::env;
-
+use chrono::Utc;
 use diesel::prelude::*;
 use diesel::result::Error;
-use diesel::PgConnection;
-use dotenvy::dotenv;
 use log::warn;
 
 use crate::content::{NewPost, Post};
-use crate::schema::artposts;
+use crate::db::{with_conn, DbPool, UtcTime};
 use crate::schema::artposts::dsl::*;
+use crate::schema::{artposts, media_files};
+
+/// Stores the posts the bot delivers, along with the file_ids Telegram gave
+/// their media.
+pub trait ArtVault: Send {
+    fn save(&mut self, p: &Post);
+
+    fn fetch(&mut self, post_id: &str) -> Option<Post>;
+
+    /// The file_id Telegram gave the media at `href` when it was first sent,
+    /// which lets it be sent again without Telegram fetching it anew.
+    fn file_id(&mut self, href: &str) -> Option<String>;
 
-pub struct ArtVault {
-    db: PgConnection,
+    fn save_file_id(&mut self, href: &str, file_id: &str);
+
+    fn forget_file_id(&mut self, href: &str);
 }
 
-impl ArtVault {
-    pub fn instance() -> Self {
-        Self {
-            db: Self::db_instance(),
-        }
+/// The `ArtVault` kept in the bot's database, Postgres or SQLite.
+pub struct SqlArtVault {
+    db: DbPool,
+}
+
+impl SqlArtVault {
+    pub fn from(pool: &DbPool) -> Self {
+        Self { db: pool.clone() }
     }
+}
 
-    pub fn save(&mut self, p: &Post) {
+impl ArtVault for SqlArtVault {
+    fn save(&mut self, p: &Post) {
         let new_post = NewPost {
             id: p.id.to_string(),
             media_href: p.media_href.to_string(),
@@ -29,11 +44,12 @@ impl ArtVault {
             author: p.author.to_string(),
             ups: p.ups,
             downs: p.downs,
+            posted_at: p.posted_at.map(UtcTime),
         };
 
-        let res = diesel::insert_into(artposts::table)
+        let res = with_conn!(self.db, |conn| diesel::insert_into(artposts::table)
             .values(&new_post)
-            .execute(&mut self.db);
+            .execute(conn));
         if res.is_err() {
             match res.err().unwrap() {
                 Error::DatabaseError(kind, info) => match kind {
@@ -47,8 +63,8 @@ impl ArtVault {
         }
     }
 
-    pub fn fetch(&mut self, post_id: &str) -> Option<Post> {
-        let found_post = artposts.find(post_id).get_result(&mut self.db);
+    fn fetch(&mut self, post_id: &str) -> Option<Post> {
+        let found_post = with_conn!(self.db, |conn| artposts.find(post_id).get_result(conn));
 
         if found_post.is_ok() {
             Some(found_post.unwrap())
@@ -57,10 +73,40 @@ impl ArtVault {
         }
     }
 
-    fn db_instance() -> PgConnection {
-        dotenv().ok();
-        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-        PgConnection::establish(&database_url)
-            .unwrap_or_else(|_| panic!("Error connecting to {}", database_url))
+    fn file_id(&mut self, href: &str) -> Option<String> {
+        with_conn!(self.db, |conn| media_files::table
+            .find(href)
+            .select(media_files::file_id)
+            .get_result(conn))
+        .ok()
+    }
+
+    fn save_file_id(&mut self, href: &str, file_id: &str) {
+        let now = UtcTime(Utc::now());
+        let res = with_conn!(self.db, |conn| diesel::insert_into(media_files::table)
+            .values((
+                media_files::media_href.eq(href),
+                media_files::file_id.eq(file_id),
+            ))
+            .on_conflict(media_files::media_href)
+            .do_update()
+            .set((
+                media_files::file_id.eq(file_id),
+                media_files::cached_at.eq(now),
+            ))
+            .execute(conn));
+        if let Err(e) = res {
+            warn!("couldn't cache file_id for \"{}\": {}", href, e);
+        }
+    }
+
+    fn forget_file_id(&mut self, href: &str) {
+        let res = with_conn!(self.db, |conn| diesel::delete(
+            media_files::table.find(href)
+        )
+        .execute(conn));
+        if let Err(e) = res {
+            warn!("couldn't drop cached file_id for \"{}\": {}", href, e);
+        }
     }
 }
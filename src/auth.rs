@@ -1,12 +1,10 @@
-use std::env;
-
 use diesel::prelude::*;
 use diesel::result::DatabaseErrorKind::UniqueViolation;
 use diesel::result::Error;
-use dotenvy::dotenv;
 use log::warn;
 use teloxide::types::UserId;
 
+use crate::db::{with_conn, DbPool};
 use crate::schema::botclients;
 
 #[derive(Copy, Clone, PartialEq, Debug)]
@@ -54,29 +52,34 @@ impl PartialEq for BotClient {
     }
 }
 
-pub struct ClientManager {
-    db: PgConnection,
-    existing: Vec<BotClient>,
+/// Keeps track of the chats and users the bot talks to.
+pub trait ClientManager: Send {
+    fn get(&mut self, user: ClientID) -> Option<&BotClient>;
+
+    fn add(&mut self, new_user: BotClient);
 }
 
-impl ClientManager {
-    pub fn instance() -> Self {
-        dotenv().ok();
-        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-        let db = PgConnection::establish(&database_url)
-            .unwrap_or_else(|_| panic!("Error connecting to {}", database_url));
+/// The `ClientManager` kept in the bot's database, Postgres or SQLite.
+pub struct SqlClientManager {
+    db: DbPool,
+    existing: Vec<BotClient>,
+}
 
+impl SqlClientManager {
+    pub fn from(pool: &DbPool) -> Self {
         Self {
-            db,
+            db: pool.clone(),
             existing: vec![],
         }
     }
+}
 
-    pub fn get(&mut self, user: ClientID) -> Option<&BotClient> {
+impl ClientManager for SqlClientManager {
+    fn get(&mut self, user: ClientID) -> Option<&BotClient> {
         use crate::auth::*;
         use crate::schema::botclients::dsl::*;
 
-        let client = botclients.find(user.0).get_result(&mut self.db);
+        let client = with_conn!(self.db, |conn| botclients.find(user.0).get_result(conn));
         if let Ok(cli) = client {
             self.existing.push(cli);
             let end = self.existing.len() - 1;
@@ -85,7 +88,7 @@ impl ClientManager {
         None
     }
 
-    pub fn add(&mut self, new_user: BotClient) {
+    fn add(&mut self, new_user: BotClient) {
         let username = new_user.username;
         let new_client = NewClient {
             id: new_user.id.id(),
@@ -93,9 +96,9 @@ impl ClientManager {
             is_user: new_user.is_user,
         };
 
-        let res = diesel::insert_into(botclients::table)
+        let res = with_conn!(self.db, |conn| diesel::insert_into(botclients::table)
             .values(&new_client)
-            .execute(&mut self.db);
+            .execute(conn));
         if res.is_err() {
             match res.err().unwrap() {
                 Error::DatabaseError(kind, info) => match kind {
@@ -115,13 +118,9 @@ impl ClientManager {
 
 #[test]
 fn test_client_manager() {
-    dotenv().ok();
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let conn = PgConnection::establish(&database_url)
-        .unwrap_or_else(|_| panic!("Error connecting to {}", database_url));
-
+    let db = crate::testing::TestDb::new();
     let username = Some("Vanessa".to_string());
-    let mut client_manager = ClientManager::instance();
+    let mut client_manager = SqlClientManager::from(&db.pool);
     client_manager.add(BotClient {
         id: ClientID(89999222654),
         username: username.clone(),
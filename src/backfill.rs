@@ -0,0 +1,311 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use log::{error, info, warn};
+
+use crate::auth::ClientID;
+use crate::curator::Curator;
+use crate::db::{self, DbPool, Storage};
+use crate::delivery::{ChatLimits, DigestQueue};
+use crate::listings::reddit::Listing;
+use crate::listings::source::{ListingSource, SourceError};
+use crate::retry::DeliveryQueue;
+use crate::seen::{SeenStore, SEEN_CAPACITY};
+
+pub const BACKFILL_COUNT_DEFAULT: u32 = 50;
+
+pub const BACKFILL_COUNT_MAX: u32 = 500;
+
+// Posts requested per page while walking back; Reddit serves at most 100.
+const BACKFILL_PAGE_SIZE: u64 = 25;
+
+// Minimum spacing between two backfilled posts, so history trickles in
+// between live posts instead of flooding the chat.
+const BACKFILL_DRIP_INTERVAL: i64 = 60;
+
+/// How far back a backfill goes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BackfillLimit {
+    /// The most recent `n` posts.
+    Count(u32),
+    /// Posts submitted since the given time, at most `BACKFILL_COUNT_MAX`.
+    /// Only `new` listings are in the order posts were submitted, so only
+    /// they can be walked back to a date.
+    Since(DateTime<Utc>),
+}
+
+impl BackfillLimit {
+    /// Parses a post count or a `YYYY-MM-DD` date, defaulting to the last
+    /// `BACKFILL_COUNT_DEFAULT` posts.
+    pub fn from(arg: Option<&str>) -> Option<BackfillLimit> {
+        let arg = match arg {
+            Some(arg) => arg,
+            None => return Some(BackfillLimit::Count(BACKFILL_COUNT_DEFAULT)),
+        };
+        if let Ok(n) = arg.parse::<u32>() {
+            return match n {
+                0 => None,
+                n => Some(BackfillLimit::Count(n.min(BACKFILL_COUNT_MAX))),
+            };
+        }
+        let date = NaiveDate::parse_from_str(arg, "%Y-%m-%d").ok()?;
+        Some(BackfillLimit::Since(date.and_hms_opt(0, 0, 0)?.and_utc()))
+    }
+
+    fn count(&self) -> usize {
+        match self {
+            BackfillLimit::Count(n) => *n as usize,
+            BackfillLimit::Since(_) => BACKFILL_COUNT_MAX as usize,
+        }
+    }
+
+    fn covers(&self, posted_at: Option<DateTime<Utc>>) -> bool {
+        match (self, posted_at) {
+            (BackfillLimit::Since(since), Some(posted_at)) => posted_at >= *since,
+            _ => true,
+        }
+    }
+}
+
+/// Time between two backfilled posts: the drip interval or a share of the
+/// chat's hourly budget, whichever is longer.
+fn drip_spacing(limits: &ChatLimits) -> chrono::Duration {
+    let spacing = chrono::Duration::seconds(BACKFILL_DRIP_INTERVAL);
+    match limits.hourly_budget.filter(|b| *b > 0) {
+        Some(budget) => spacing.max(chrono::Duration::seconds(3600 / budget as i64)),
+        None => spacing,
+    }
+}
+
+/// When each of `n` backfilled posts may be sent, starting at `start`: one
+/// per `drip_spacing` and never during the chat's quiet hours.
+pub fn drip_schedule(limits: &ChatLimits, start: DateTime<Utc>, n: usize) -> Vec<DateTime<Utc>> {
+    let spacing = drip_spacing(limits);
+    let mut at = start;
+    let mut schedule = Vec::with_capacity(n);
+    for _ in 0..n {
+        // Quiet hours span whole hours, so a day of steps always leaves them.
+        for _ in 0..24 {
+            if !limits.is_quiet(at) {
+                break;
+            }
+            at += chrono::Duration::hours(1);
+        }
+        schedule.push(at);
+        at += spacing;
+    }
+    schedule
+}
+
+/// Walks a listing back through its older pages on behalf of one chat and
+/// queues what it finds for the `RetryWorker`, which then delivers it a
+/// little at a time alongside the chat's live posts.
+pub struct Backfill {
+    chat: ClientID,
+    listing: Listing,
+    limit: BackfillLimit,
+}
+
+impl Backfill {
+    /// Random listings have no history to walk and are refused, as are dates
+    /// on listings other than `new`.
+    pub fn from(chat: ClientID, mut listing: Listing, limit: BackfillLimit) -> Option<Self> {
+        if matches!(limit, BackfillLimit::Since(_)) && !matches!(listing, Listing::New { .. }) {
+            return None;
+        }
+        let paginator = listing.paginator()?;
+        paginator.seek_back();
+        paginator.set_limit(BACKFILL_PAGE_SIZE);
+        Some(Self {
+            chat,
+            listing,
+            limit,
+        })
+    }
+
+    pub fn listing(&self) -> &Listing {
+        &self.listing
+    }
+
+    /// Returns how many posts were queued for delivery, leaving out those
+    /// the chat already got or has queued, or why nothing could be found.
+    pub async fn run<T: ListingSource>(
+        mut self,
+        curator: Curator<T>,
+        pool: DbPool,
+    ) -> Result<usize, SourceError> {
+        let sub = self.listing.subreddit();
+        let mut found = Vec::new();
+        'pages: while found.len() < self.limit.count() {
+            let posts = match curator.fetch(&mut self.listing).await {
+                Ok(posts) => posts,
+                Err(e) if found.is_empty() => return Err(e),
+                Err(e) => {
+                    error!("Backfill of `{}` stopped early: {}", sub, e);
+                    break;
+                }
+            };
+            if posts.is_empty() {
+                break;
+            }
+            for post in posts {
+                if !self.limit.covers(post.posted_at) || found.len() >= self.limit.count() {
+                    break 'pages;
+                }
+                found.push(post);
+            }
+            if self.listing.paginator().is_none_or(|p| p.is_exhausted()) {
+                break;
+            }
+        }
+
+        if found.is_empty() {
+            warn!("Nothing to backfill from `{}`", sub);
+            return Ok(0);
+        }
+
+        // Oldest first, so the history reads in the order it was posted.
+        found.reverse();
+        let key = curator.feed_key(&self.listing);
+        let (chat, listing) = (self.chat, self.listing);
+        let queued = db::blocking(move || {
+            let mut deliveries = DeliveryQueue::from(&pool);
+            let mut known = deliveries.pending(chat);
+            // The chat's own feed of the listing already sent what it saw.
+            let subscribed = pool.subscriptions().listings(chat).map(|listings| {
+                listings.iter().any(|l| {
+                    l.subreddit() == listing.subreddit() && l.category() == listing.category()
+                })
+            });
+            if subscribed.unwrap_or_else(|e| {
+                error!(
+                    "error loading subscriptions of ChatID: '{}': {}",
+                    chat.id(),
+                    e
+                );
+                false
+            }) {
+                let seen = SeenStore::from(&pool).load(&key, SEEN_CAPACITY);
+                known.extend(
+                    found
+                        .iter()
+                        .filter(|post| seen.contains(post.id()))
+                        .map(|post| post.id().clone()),
+                );
+            }
+            found.retain(|post| !known.contains(post.id()));
+            if found.is_empty() {
+                return 0;
+            }
+
+            let limits = DigestQueue::from(&pool).limits(chat);
+            // After what's already scheduled, so an earlier backfill still
+            // dripping in isn't interleaved with this one.
+            let now = Utc::now();
+            let start = match deliveries.last_scheduled(chat) {
+                Some(last) if last >= now => last + drip_spacing(&limits),
+                _ => now,
+            };
+            let schedule = drip_schedule(&limits, start, found.len());
+
+            let mut vault = pool.vault();
+            for (post, at) in found.iter().zip(schedule) {
+                if vault.fetch(post.id()).is_none() {
+                    vault.save(post);
+                }
+                deliveries.schedule(chat, post, at);
+            }
+            found.len()
+        })
+        .await;
+        info!(
+            "Backfilling {} post(s) from `{}` to ChatID: '{}'",
+            queued,
+            sub,
+            chat.id()
+        );
+        Ok(queued)
+    }
+}
+
+#[test]
+fn test_backfill_limits() {
+    use chrono::TimeZone;
+
+    assert_eq!(
+        BackfillLimit::from(None),
+        Some(BackfillLimit::Count(BACKFILL_COUNT_DEFAULT))
+    );
+    assert_eq!(
+        BackfillLimit::from(Some("10000")),
+        Some(BackfillLimit::Count(BACKFILL_COUNT_MAX))
+    );
+    assert_eq!(BackfillLimit::from(Some("0")), None);
+    let since = Utc.with_ymd_and_hms(2023, 5, 1, 0, 0, 0).unwrap();
+    assert_eq!(
+        BackfillLimit::from(Some("2023-05-01")),
+        Some(BackfillLimit::Since(since))
+    );
+    assert!(!BackfillLimit::Since(since).covers(Some(since - chrono::Duration::seconds(1))));
+
+    // Only `new` listings can be walked back to a date.
+    let chat = ClientID::from(42);
+    let listing = |category| Listing::parse(category, "Art".into()).unwrap();
+    assert!(Backfill::from(chat, listing("new"), BackfillLimit::Since(since)).is_some());
+    assert!(Backfill::from(chat, listing("top/week"), BackfillLimit::Since(since)).is_none());
+    assert!(Backfill::from(chat, listing("top/week"), BackfillLimit::Count(10)).is_some());
+    assert!(Backfill::from(chat, listing("random"), BackfillLimit::Count(10)).is_none());
+
+    // Ten posts an hour spaces them six minutes apart, skipping 22..7.
+    let limits = ChatLimits {
+        quiet_hours: Some((22, 7)),
+        hourly_budget: Some(10),
+        ..Default::default()
+    };
+    let start = Utc.with_ymd_and_hms(2023, 5, 1, 21, 54, 0).unwrap();
+    let schedule = drip_schedule(&limits, start, 3);
+    assert_eq!(schedule[0], start);
+    assert_eq!(
+        schedule[1],
+        Utc.with_ymd_and_hms(2023, 5, 2, 7, 0, 0).unwrap()
+    );
+    assert_eq!(
+        schedule[2],
+        Utc.with_ymd_and_hms(2023, 5, 2, 7, 6, 0).unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_backfill_skips_known_posts() {
+    use crate::testing::{post, MockSource, TestDb};
+
+    let db = TestDb::new();
+    let src = MockSource::default();
+    let curator = Curator::from(src.clone(), &db.pool);
+    let (chat, listing) = (
+        ClientID::from(-42),
+        Listing::parse("new", "Art".into()).unwrap(),
+    );
+    db.pool.clients().add(crate::auth::BotClient {
+        id: chat,
+        username: None,
+        is_user: false,
+    });
+    db.pool.subscriptions().subscribe(chat, &listing).unwrap();
+    // The chat's feed already sent `a`, and `b` is still waiting to be sent.
+    SeenStore::from(&db.pool).record(&curator.feed_key(&listing), &["a".to_string()], &[]);
+    db.pool.vault().save(&post("b"));
+    DeliveryQueue::from(&db.pool).schedule(chat, &post("b"), Utc::now());
+
+    let backfill = || Backfill::from(chat, listing.clone(), BackfillLimit::Count(10)).unwrap();
+    for queued in [1, 0] {
+        src.push_page("Art", vec![post("c"), post("b"), post("a")]);
+        let res = backfill().run(curator.clone(), db.pool.clone()).await;
+        assert_eq!(res.unwrap(), queued);
+    }
+    let mut pending = DeliveryQueue::from(&db.pool).pending(chat);
+    pending.sort();
+    assert_eq!(pending, vec!["b", "c"]);
+
+    src.remove("Art");
+    let res = backfill().run(curator.clone(), db.pool.clone()).await;
+    assert!(matches!(res, Err(SourceError::NotFound)));
+}
@@ -0,0 +1,352 @@
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use dotenvy::dotenv;
+use serde::Deserialize;
+
+use crate::db;
+use crate::listings::reddit::{Credentials, Grant};
+use crate::outbound::SendLimits;
+use crate::polling::PollLimits;
+
+/// Read when `ARTBUTLER_CONFIG` doesn't name another file. Unlike a file
+/// named that way, it may be missing, leaving everything to the environment.
+pub const CONFIG_PATH_DEFAULT: &str = "artbutler.toml";
+
+/// Everything the bot is configured with, from its TOML file and then the
+/// environment, which overrides it. Checked once, before the bot starts.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub telegram: TelegramConfig,
+    pub database: DatabaseConfig,
+    pub reddit: RedditConfig,
+    pub polling: PollLimits,
+    pub delivery: SendLimits,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelegramConfig {
+    /// The bot's token, `TELOXIDE_TOKEN`.
+    pub token: String,
+    /// Users allowed to inspect the bot with `/polling`, as a comma separated
+    /// `ADMIN_IDS`. Nobody may while there are none.
+    pub admins: Vec<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// `DATABASE_URL`, to Postgres or a SQLite file.
+    pub url: String,
+}
+
+/// The Reddit app the bot reads listings with. Which of the optional
+/// settings are needed depends on `grant`, see `Grant`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedditConfig {
+    pub client_id: String,
+    pub secret: Option<String>,
+    /// `password`, `client_credentials`, `installed_client` or
+    /// `authorization_code`. Defaults to `password` when a username is set.
+    pub grant: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub device_id: Option<String>,
+    pub auth_code: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(String, std::io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "couldn't read `{}`: {}", path, e),
+            ConfigError::Parse(e) => write!(f, "invalid config file: {}", e),
+            ConfigError::Invalid(reason) => write!(f, "invalid config: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn invalid<T>(reason: impl Into<String>) -> Result<T, ConfigError> {
+    Err(ConfigError::Invalid(reason.into()))
+}
+
+impl Config {
+    /// Reads the config file, applies the environment, including a `.env`
+    /// file, over it and validates the result.
+    pub fn load() -> Result<Config, ConfigError> {
+        dotenv().ok();
+
+        let (path, required) = match env::var("ARTBUTLER_CONFIG") {
+            Ok(path) => (path, true),
+            Err(_) => (CONFIG_PATH_DEFAULT.to_string(), false),
+        };
+        let mut config = match std::fs::read_to_string(&path) {
+            Ok(text) => Config::parse(&text)?,
+            Err(e) if required || e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(ConfigError::Read(path, e));
+            }
+            Err(_) => Config::default(),
+        };
+        config.override_with(|name| env::var(name).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn parse(text: &str) -> Result<Config, ConfigError> {
+        toml::from_str(text).map_err(ConfigError::Parse)
+    }
+
+    /// Replaces settings by the variables `var` finds, by their names.
+    pub fn override_with(
+        &mut self,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<(), ConfigError> {
+        fn set<T: FromStr>(
+            setting: &mut T,
+            name: &str,
+            var: &impl Fn(&str) -> Option<String>,
+        ) -> Result<(), ConfigError> {
+            if let Some(value) = var(name) {
+                *setting = match value.trim().parse() {
+                    Ok(value) => value,
+                    Err(_) => return invalid(format!("can't parse {}=`{}`", name, value)),
+                };
+            }
+            Ok(())
+        }
+        fn set_opt(
+            setting: &mut Option<String>,
+            name: &str,
+            var: &impl Fn(&str) -> Option<String>,
+        ) {
+            if let Some(value) = var(name) {
+                *setting = Some(value);
+            }
+        }
+
+        set(&mut self.telegram.token, "TELOXIDE_TOKEN", &var)?;
+        if let Some(ids) = var("ADMIN_IDS") {
+            self.telegram.admins = ids
+                .split(',')
+                .filter(|id| !id.trim().is_empty())
+                .map(|id| id.trim().parse())
+                .collect::<Result<_, _>>()
+                .or_else(|_| invalid(format!("can't parse ADMIN_IDS=`{}`", ids)))?;
+        }
+        set(&mut self.database.url, "DATABASE_URL", &var)?;
+
+        let reddit = &mut self.reddit;
+        set(&mut reddit.client_id, "CLIENT_ID", &var)?;
+        set_opt(&mut reddit.secret, "SECRET", &var);
+        set_opt(&mut reddit.grant, "REDDIT_GRANT", &var);
+        set_opt(&mut reddit.username, "USER_NAME", &var);
+        set_opt(&mut reddit.password, "PASSWORD", &var);
+        set_opt(&mut reddit.device_id, "REDDIT_DEVICE_ID", &var);
+        set_opt(&mut reddit.auth_code, "REDDIT_AUTH_CODE", &var);
+        set_opt(&mut reddit.redirect_uri, "REDDIT_REDIRECT_URI", &var);
+        set_opt(&mut reddit.refresh_token, "REDDIT_REFRESH_TOKEN", &var);
+
+        set(&mut self.polling.min_interval, "POLL_INTERVAL_MIN", &var)?;
+        set(&mut self.polling.max_interval, "POLL_INTERVAL_MAX", &var)?;
+
+        let delivery = &mut self.delivery;
+        set(
+            &mut delivery.global_per_second,
+            "SEND_GLOBAL_PER_SECOND",
+            &var,
+        )?;
+        set(&mut delivery.chat_per_second, "SEND_CHAT_PER_SECOND", &var)?;
+        set(
+            &mut delivery.group_per_minute,
+            "SEND_GROUP_PER_MINUTE",
+            &var,
+        )?;
+        Ok(())
+    }
+
+    /// Checks every setting is there and makes sense, so the bot fails on
+    /// startup rather than once a setting is first needed.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.telegram.token.is_empty() {
+            return invalid("telegram.token (TELOXIDE_TOKEN) must be set");
+        }
+        if self.database.url.is_empty() {
+            return invalid("database.url (DATABASE_URL) must be set");
+        }
+        if !db::supports(&self.database.url) {
+            return invalid("database.url must be a postgres:// or sqlite:// url");
+        }
+        self.reddit.credentials()?;
+
+        let polling = &self.polling;
+        if polling.min_interval == 0 || polling.min_interval > polling.max_interval {
+            return invalid(format!(
+                "polling intervals must be positive with min_interval <= max_interval, got {}..{}",
+                polling.min_interval, polling.max_interval
+            ));
+        }
+        let delivery = &self.delivery;
+        let rates = [
+            delivery.global_per_second,
+            delivery.chat_per_second,
+            delivery.group_per_minute,
+        ];
+        if rates.iter().any(|rate| !rate.is_finite() || *rate <= 0.0) {
+            return invalid("delivery rates must be positive");
+        }
+        Ok(())
+    }
+
+    pub fn is_admin(&self, user: u64) -> bool {
+        self.telegram.admins.contains(&user)
+    }
+}
+
+impl RedditConfig {
+    pub fn credentials(&self) -> Result<Credentials, ConfigError> {
+        // Left empty, as in the example file, a setting isn't set.
+        fn set(setting: &Option<String>) -> Option<String> {
+            setting.clone().filter(|value| !value.trim().is_empty())
+        }
+        fn required(setting: &Option<String>, name: &str) -> Result<String, ConfigError> {
+            match set(setting) {
+                Some(value) => Ok(value),
+                None => invalid(format!("reddit.{} must be set", name)),
+            }
+        }
+
+        if self.client_id.trim().is_empty() {
+            return invalid("reddit.client_id (CLIENT_ID) must be set");
+        }
+        let grant = match set(&self.grant) {
+            Some(grant) => grant,
+            None if set(&self.username).is_some() => "password".to_string(),
+            None => "client_credentials".to_string(),
+        };
+        let grant = match grant.as_str() {
+            "password" => Grant::Password {
+                username: required(&self.username, "username")?,
+                password: required(&self.password, "password")?,
+            },
+            "client_credentials" => Grant::ClientCredentials,
+            "installed_client" => Grant::InstalledClient {
+                device_id: set(&self.device_id)
+                    .unwrap_or_else(|| "DO_NOT_TRACK_THIS_DEVICE".to_string()),
+            },
+            "authorization_code" => {
+                let (code, refresh_token) = (set(&self.auth_code), set(&self.refresh_token));
+                if code.is_none() && refresh_token.is_none() {
+                    return invalid("reddit.auth_code or reddit.refresh_token must be set");
+                }
+                Grant::AuthorizationCode {
+                    code,
+                    redirect_uri: required(&self.redirect_uri, "redirect_uri")?,
+                    refresh_token,
+                }
+            }
+            grant => return invalid(format!("unknown reddit.grant `{}`", grant)),
+        };
+        let secret = match grant {
+            Grant::InstalledClient { .. } => String::new(),
+            _ => required(&self.secret, "secret")?,
+        };
+        Ok(Credentials {
+            client_id: self.client_id.to_string(),
+            secret,
+            grant,
+        })
+    }
+}
+
+#[test]
+fn test_config() {
+    let text = r#"
+        [telegram]
+        token = "123:abc"
+        admins = [1]
+
+        [database]
+        url = "sqlite://artbutler.db"
+
+        [reddit]
+        client_id = "id"
+        secret = "secret"
+
+        [polling]
+        max_interval = 600
+    "#;
+    let mut config = Config::parse(text).unwrap();
+    assert!(config.validate().is_ok());
+    assert!(config.is_admin(1));
+    assert_eq!(
+        config.polling.min_interval,
+        PollLimits::default().min_interval
+    );
+    assert_eq!(config.polling.max_interval, 600);
+    assert_eq!(
+        config.reddit.credentials().unwrap().grant,
+        Grant::ClientCredentials
+    );
+
+    // The environment wins over the file.
+    let env = |name: &str| match name {
+        "ADMIN_IDS" => Some("2, 3".to_string()),
+        "USER_NAME" => Some("bot".to_string()),
+        "PASSWORD" => Some("hunter2".to_string()),
+        "POLL_INTERVAL_MIN" => Some("30".to_string()),
+        _ => None,
+    };
+    config.override_with(env).unwrap();
+    assert!(!config.is_admin(1) && config.is_admin(3));
+    assert_eq!(config.polling.min_interval, 30);
+    assert_eq!(
+        config.reddit.credentials().unwrap().grant,
+        Grant::Password {
+            username: "bot".to_string(),
+            password: "hunter2".to_string()
+        }
+    );
+
+    let example = Config::parse(include_str!("../artbutler.example.toml")).unwrap();
+    assert_eq!(example.polling, PollLimits::default());
+    assert_eq!(example.delivery, SendLimits::default());
+    assert!(example.telegram.admins.is_empty());
+    assert!(!example.is_admin(1));
+
+    // Mistakes are caught before anything starts.
+    assert!(Config::parse("[telegram]\ntokn = \"typo\"").is_err());
+    assert!(Config::default().validate().is_err());
+    let unparsable = |name: &str| (name == "POLL_INTERVAL_MAX").then(|| "soon".to_string());
+    assert!(config.clone().override_with(unparsable).is_err());
+    config.polling.min_interval = 900;
+    assert!(config.validate().is_err());
+    config.polling.min_interval = 30;
+    config.reddit.grant = Some("installed_client".to_string());
+    config.reddit.secret = None;
+    assert_eq!(config.reddit.credentials().unwrap().secret, "");
+    // Empty settings, as left in the example file, count as missing.
+    config.reddit.grant = Some("password".to_string());
+    config.reddit.password = Some("".to_string());
+    config.reddit.secret = Some("secret".to_string());
+    assert!(config.reddit.credentials().is_err());
+    config.reddit.grant = None;
+    config.reddit.password = Some("hunter2".to_string());
+    config.reddit.secret = Some(" ".to_string());
+    assert!(config.reddit.credentials().is_err());
+    config.database.url = "mysql://localhost/artbutler".to_string();
+    assert!(config.validate().is_err());
+}
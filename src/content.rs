@@ -1,8 +1,10 @@
 use std::fmt::Formatter;
 use std::hash::Hasher;
 
+use chrono::{DateTime, Utc};
 use diesel::prelude::*;
 
+use crate::db::UtcTime;
 use crate::schema::artposts;
 
 #[derive(Insertable, Debug, Clone, PartialEq)]
@@ -14,6 +16,7 @@ pub struct NewPost {
     pub author: String,
     pub ups: i32,
     pub downs: i32,
+    pub posted_at: Option<UtcTime>,
 }
 
 #[derive(Queryable, Debug, Clone, Eq)]
@@ -24,6 +27,7 @@ pub struct Post {
     pub author: String,
     pub ups: i32,
     pub downs: i32,
+    pub posted_at: Option<DateTime<Utc>>,
 }
 
 impl Post {
@@ -41,18 +45,14 @@ impl Post {
             author,
             ups: vote_count.0,
             downs: vote_count.1,
+            posted_at: None,
         }
     }
 
-    pub fn empty() -> Self {
-        Post {
-            id: String::new(),
-            media_href: String::new(),
-            title: String::new(),
-            author: String::new(),
-            ups: 0,
-            downs: 0,
-        }
+    /// Sets when the post was submitted to its source.
+    pub fn posted(mut self, at: DateTime<Utc>) -> Self {
+        self.posted_at = Some(at);
+        self
     }
 
     pub fn id(&self) -> &String {
@@ -62,6 +62,10 @@ impl Post {
     pub fn title(&self) -> String {
         self.title.to_string()
     }
+
+    pub fn score(&self) -> i32 {
+        self.ups - self.downs
+    }
 }
 
 impl PartialEq for Post {
@@ -86,10 +90,13 @@ impl std::fmt::Display for Post {
     }
 }
 
-#[derive(Queryable, Debug)]
+#[derive(Queryable, Clone, Debug)]
 pub struct SubscribedListing {
     pub user_id: i64,
     pub subreddit: String,
     pub category: String,
     pub head_post_id: Option<String>,
+    pub delivery_mode: String,
+    pub digest_size: Option<i32>,
+    pub last_digest_at: Option<DateTime<Utc>>,
 }
@@ -1,212 +1,520 @@
-use std::collections::{HashSet, VecDeque};
-use std::sync::Arc;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Weak};
 use std::time::Duration;
 
+use chrono::Utc;
+use diesel::prelude::*;
 use log::{error, info, warn};
-use tokio::sync::mpsc::Sender;
-use tokio::sync::Mutex;
+use tokio::sync::broadcast::{self, error::RecvError, Sender};
 use tokio::task::JoinHandle;
-use tokio::time::{sleep_until, Instant};
-use tokio::{
-    spawn,
-    sync::mpsc::{channel, Receiver},
-};
-
-use crate::listings::reddit::Seek;
-use crate::listings::source::ListingSource;
+use tokio::time::{sleep_until, timeout, Instant};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+use crate::db::{self, with_conn, DbPool, UtcTime};
+use crate::listings::reddit::{Seek, Subreddit};
+use crate::listings::source::{ListingSource, SourceError};
+use crate::polling::{PollLimits, PollSchedule};
+use crate::seen::{SeenStore, SEEN_CAPACITY};
+use crate::supervisor::{supervise, TaskHealth};
 use crate::{content::Post, listings::reddit::Listing};
 
-pub const SYNC_INTERVAL_MAX: u64 = 32;
+const RETRY_INTERVAL: u64 = 10;
+
+const AUTH_RETRY_INTERVAL: u64 = 60;
 
-pub const SYNC_INTERVAL_DEFAULT: u64 = 1;
+// Posts a slow subscriber may fall behind a feed before skipping some.
+const FEED_CAPACITY: usize = 32;
 
-struct Buffer {
-    buf: VecDeque<Post>,
-    size: usize,
+// How long feeds get to save their cursors once asked to shut down.
+const SHUTDOWN_GRACE: u64 = 10;
+
+/// Identifies a listing polled once on behalf of all its subscribers.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FeedKey {
+    pub source: &'static str,
+    pub target: Subreddit,
+    pub category: String,
 }
 
-impl Buffer {
-    fn new(len: usize) -> Self {
-        if len == 0 {
-            panic!("buffer must be of size > 0");
-        }
+impl FeedKey {
+    fn from(source: &'static str, listing: &Listing) -> Self {
         Self {
-            buf: VecDeque::with_capacity(len),
-            size: len,
+            source,
+            target: listing.subreddit(),
+            category: listing.category(),
         }
     }
+}
 
-    fn is_full(&self) -> bool {
-        self.buf.len() >= self.size
+impl std::fmt::Display for FeedKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "`{}` {}/{}", self.source, self.target, self.category)
     }
+}
 
-    fn difference(&self, other: VecDeque<Post>) -> Vec<Post> {
-        let mut buf_set = HashSet::new();
+/// Where each listing's polling left off, so it can pick up from there
+/// after a restart instead of skipping or redelivering posts.
+pub struct CursorStore {
+    db: DbPool,
+}
 
-        for post in self.buf.iter() {
-            buf_set.insert(post);
-        }
+impl CursorStore {
+    pub fn from(pool: &DbPool) -> Self {
+        Self { db: pool.clone() }
+    }
 
-        let mut diff = Vec::new();
-        for post in other {
-            if !buf_set.contains(&post) {
-                diff.push(post)
-            }
+    pub fn load(&mut self, key: &FeedKey) -> Option<String> {
+        use crate::schema::listing_cursors::dsl::*;
+
+        with_conn!(self.db, |conn| listing_cursors
+            .find((key.source, key.target.name(), &key.category))
+            .select(anchor)
+            .first::<String>(conn)
+            .optional())
+        .unwrap_or_else(|e| {
+            error!("error loading cursor of {}: {}", key, e);
+            None
+        })
+    }
+
+    pub fn save(&mut self, key: &FeedKey, cursor: &str) {
+        use crate::schema::listing_cursors::dsl::*;
+
+        let now = UtcTime(Utc::now());
+        let res = with_conn!(self.db, |conn| diesel::insert_into(listing_cursors)
+            .values((
+                source.eq(key.source),
+                subreddit.eq(key.target.name()),
+                category.eq(&key.category),
+                anchor.eq(cursor),
+                updated_at.eq(now),
+            ))
+            .on_conflict((source, subreddit, category))
+            .do_update()
+            .set((anchor.eq(cursor), updated_at.eq(now)))
+            .execute(conn));
+        if let Err(e) = res {
+            error!("couldn't save cursor of {}: {}", key, e);
         }
+    }
+}
+
+/// What subscribers of a feed are told.
+#[derive(Debug, Clone)]
+pub enum FeedEvent {
+    Post(Post),
+    /// The listing can't be polled anymore, e.g. its subreddit was banned or
+    /// went private, and the feed stopped.
+    Gone(String),
+}
+
+struct Feed {
+    key: FeedKey,
+    tx: Sender<FeedEvent>,
+    /// The supervisor keeping the feed's listener running.
+    task: JoinHandle<()>,
+    cancel: CancellationToken,
+    schedule: Arc<std::sync::Mutex<PollSchedule>>,
+    health: Arc<std::sync::Mutex<TaskHealth>>,
+}
 
-        diff
+type FeedMap = Arc<std::sync::Mutex<HashMap<FeedKey, Weak<Feed>>>>;
+
+/// How a listing's feed is doing and how often it's polled, as currently
+/// learnt from its posts.
+#[derive(Debug, Clone)]
+pub struct PollStatus {
+    pub listing: String,
+    pub interval: Duration,
+    /// Average time between two posts, once a couple were seen.
+    pub mean_gap: Option<Duration>,
+    pub health: TaskHealth,
+}
+
+impl Drop for Feed {
+    fn drop(&mut self) {
+        info!("No subscribers left for {}, stopping its feed", self.key);
+        // The listener saves its cursor before it stops.
+        self.cancel.cancel();
     }
+}
 
-    fn insert(&mut self, p: Post) {
-        if self.is_full() {
-            self.buf.push_back(p);
-            self.buf.pop_front();
-            return;
+/// A subscriber's end of a shared feed. The listing stops being polled once
+/// every `Subscription` to it is dropped.
+pub struct Subscription {
+    rx: broadcast::Receiver<FeedEvent>,
+    feed: Arc<Feed>,
+}
+
+impl Subscription {
+    pub async fn recv(&mut self) -> Option<FeedEvent> {
+        loop {
+            match self.rx.recv().await {
+                Ok(event) => return Some(event),
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(
+                        "Subscriber of {}/{} fell behind, skipped {} post(s)",
+                        self.feed.key.target, self.feed.key.category, skipped
+                    );
+                }
+                Err(RecvError::Closed) => return None,
+            }
         }
-        self.buf.push_back(p);
     }
 }
 
+/// Polls each listing from `src` once, however many chats subscribe to it,
+/// and broadcasts new posts to all of them.
+#[derive(Clone)]
 pub struct Curator<T> {
     src: T,
-    curations: Vec<JoinHandle<()>>,
-    pub chan: (Sender<Post>, Receiver<Post>),
+    db: DbPool,
+    limits: PollLimits,
+    feeds: FeedMap,
+    shutdown: CancellationToken,
+    tracker: TaskTracker,
 }
 
 impl<T: ListingSource> Curator<T> {
-    pub fn from(src: T) -> Self {
+    pub fn from(src: T, pool: &DbPool) -> Self {
         Curator {
             src,
-            curations: vec![],
-            chan: channel(5),
+            db: pool.clone(),
+            limits: PollLimits::default(),
+            feeds: Default::default(),
+            shutdown: CancellationToken::new(),
+            tracker: TaskTracker::new(),
         }
     }
 
-    pub fn spawn_for(&mut self, listing: Arc<Mutex<Listing>>) {
-        let api = self.src.clone();
-        let tx = self.chan.0.clone();
-        let task = spawn(Self::listing_listener(
-            api,
-            tx,
-            listing,
-            SYNC_INTERVAL_DEFAULT,
-        ));
-        self.curations.push(task);
+    /// Keeps the learnt polling intervals of feeds within `limits`.
+    pub fn with_limits(mut self, limits: PollLimits) -> Self {
+        self.limits = limits;
+        self
     }
 
-    async fn listing_listener(
-        mut api: T,
-        tx: Sender<Post>,
-        listing: Arc<Mutex<Listing>>,
-        mut sync_interval: u64,
+    /// Stops every feed, giving listeners a moment to save their cursors.
+    pub async fn shutdown(&self) {
+        info!("Stopping all feeds ...");
+        self.shutdown.cancel();
+        self.tracker.close();
+        if timeout(Duration::from_secs(SHUTDOWN_GRACE), self.tracker.wait())
+            .await
+            .is_err()
+        {
+            warn!("Some feeds didn't stop within {}s", SHUTDOWN_GRACE);
+        }
+    }
+
+    /// Subscribes to `listing`, starting to poll it unless another
+    /// subscriber already did.
+    pub fn subscribe(&self, listing: Listing) -> Subscription {
+        let key = FeedKey::from(self.src.name(), &listing);
+        let mut feeds = self.feeds.lock().unwrap();
+        feeds.retain(|_, feed| feed.strong_count() > 0);
+
+        let feed = feeds
+            .get(&key)
+            .and_then(Weak::upgrade)
+            .filter(|feed| !feed.task.is_finished());
+        match feed {
+            Some(feed) => Subscription {
+                rx: feed.tx.subscribe(),
+                feed,
+            },
+            None => {
+                info!("Starting feed for {}", key);
+                // The first subscriber's receiver exists before the listener
+                // starts, so nothing is broadcast while nobody can get it.
+                let (tx, rx) = broadcast::channel(FEED_CAPACITY);
+                let cancel = self.shutdown.child_token();
+                let health = Arc::new(std::sync::Mutex::new(TaskHealth::default()));
+                let schedule = match listing.random_interval() {
+                    Some(every) => PollSchedule::fixed(every),
+                    None => PollSchedule::new(self.limits),
+                };
+                let schedule = Arc::new(std::sync::Mutex::new(schedule));
+
+                let (curator, feed_tx, feed_key, feed_schedule, feed_cancel) = (
+                    self.clone(),
+                    tx.clone(),
+                    key.clone(),
+                    schedule.clone(),
+                    cancel.clone(),
+                );
+                let start = move || {
+                    curator.clone().listener(
+                        feed_tx.clone(),
+                        listing.clone(),
+                        feed_key.clone(),
+                        feed_schedule.clone(),
+                        feed_cancel.clone(),
+                    )
+                };
+                let task = self.tracker.spawn(supervise(
+                    key.to_string(),
+                    cancel.clone(),
+                    health.clone(),
+                    start,
+                ));
+                let feed = Arc::new(Feed {
+                    key: key.clone(),
+                    tx,
+                    task,
+                    cancel,
+                    schedule,
+                    health,
+                });
+                feeds.insert(key, Arc::downgrade(&feed));
+                Subscription { rx, feed }
+            }
+        }
+    }
+
+    /// Polls the listing until it's gone or `cancel` fires, resuming from
+    /// and saving its cursor around that.
+    async fn listener(
+        self,
+        tx: Sender<FeedEvent>,
+        mut listing: Listing,
+        key: FeedKey,
+        schedule: Arc<std::sync::Mutex<PollSchedule>>,
+        cancel: CancellationToken,
     ) {
-        let mut timeout_cnt = 0;
-        let mut buffer = Buffer::new(100);
+        if let Some(every) = listing.random_interval() {
+            tokio::select! {
+                _ = cancel.cancelled() => (),
+                _ = Self::random_listener(self.src.clone(), tx, listing, every) => (),
+            }
+            return;
+        }
 
-        loop {
-            let mut synced_posts = VecDeque::new();
-            let mut sub = "".into();
-            {
-                let mut retrieved_posts = None;
-                {
-                    let mut listing_guard = listing.lock().await;
-                    sub = listing_guard.subreddit().clone();
+        let (db, saved) = (self.db.clone(), key.clone());
+        let anchor = db::blocking(move || CursorStore::from(&db).load(&saved)).await;
+        if let Some(anchor) = anchor {
+            info!("Resuming {} from `{}`", key, anchor);
+            listing.resume(anchor);
+        }
+        tokio::select! {
+            _ = cancel.cancelled() => (),
+            _ = self.listing_listener(&tx, &mut listing, &key, &schedule) => (),
+        }
+        if let Some(anchor) = listing.anchor() {
+            let db = self.db.clone();
+            db::blocking(move || CursorStore::from(&db).save(&key, &anchor)).await;
+        }
+    }
 
-                    retrieved_posts = Some(api.retrieve_posts(&mut listing_guard).await);
-                }
+    async fn listing_listener(
+        &self,
+        tx: &Sender<FeedEvent>,
+        listing: &mut Listing,
+        key: &FeedKey,
+        schedule: &std::sync::Mutex<PollSchedule>,
+    ) {
+        let mut api = self.src.clone();
+        let sub = listing.subreddit();
+        let (db, seen_key) = (self.db.clone(), key.clone());
+        let mut seen =
+            db::blocking(move || SeenStore::from(&db).load(&seen_key, SEEN_CAPACITY)).await;
 
-                let posts = retrieved_posts.unwrap();
-                if posts.is_err() {
-                    error!("couldn't retrieve posts: {}", posts.err().unwrap());
-                    warn!("Retrying post retrieval in 10s");
-                    sleep_until(Instant::now() + Duration::from_secs(10)).await;
-                    continue;
+        loop {
+            let posts = match api.retrieve_posts(listing).await {
+                Ok(posts) => posts,
+                Err(e) => {
+                    if Self::recover(e, &sub, tx).await {
+                        continue;
+                    }
+                    break;
                 }
-
-                let mut posts = posts.unwrap();
-                {
-                    let mut listing_guard = listing.lock().await;
-                    match listing_guard.paginator().cursor() {
-                        Seek::After { .. } => {
-                            synced_posts.append(&mut posts);
-                        }
-                        Seek::Back { .. } => {
-                            for post in posts {
-                                synced_posts.push_front(post);
-                            }
-                        }
+            };
+            let mut synced_posts = VecDeque::new();
+            match listing.seek() {
+                Seek::Newer => synced_posts.extend(posts),
+                Seek::Older => {
+                    for post in posts {
+                        synced_posts.push_front(post);
                     }
                 }
             }
 
-            let new_posts = buffer.difference(synced_posts);
-            if !new_posts.is_empty() {
+            let new_posts = seen.unseen(synced_posts);
+            let now = Utc::now();
+            let posted = new_posts
+                .iter()
+                .map(|post| post.posted_at.unwrap_or(now))
+                .collect::<Vec<_>>();
+            // Every live feed gets an equal share of the API budget.
+            let floor = api.request_spacing() * self.live_feeds();
+            let interval = {
+                let mut schedule = schedule.lock().unwrap();
+                schedule.observe(&posted, now, floor);
+                schedule.jittered()
+            };
+
+            if new_posts.is_empty() {
+                info!(
+                    "No new post since last poll for `{}`, next poll in {}s",
+                    sub,
+                    interval.as_secs()
+                );
+            } else {
                 info!(
-                    "{} new post(s) found for `r/{}`! Resetting wait interval",
+                    "{} new post(s) found for `{}`, next poll in {}s",
                     new_posts.len(),
-                    sub.name(),
+                    sub,
+                    interval.as_secs()
                 );
+            }
+            if !new_posts.is_empty() {
+                let ids = new_posts
+                    .iter()
+                    .map(|post| post.id().clone())
+                    .collect::<Vec<_>>();
+                let forgotten = ids
+                    .iter()
+                    .filter_map(|id| seen.insert(id.clone()))
+                    .collect::<Vec<_>>();
+                let (db, seen_key) = (self.db.clone(), key.clone());
+                db::blocking(move || SeenStore::from(&db).record(&seen_key, &ids, &forgotten))
+                    .await;
+            }
+            for post in new_posts {
+                // Only fails when nobody is subscribed at the moment.
+                let _ = tx.send(FeedEvent::Post(post));
+            }
 
-                for post in new_posts {
-                    buffer.insert(post.clone());
-                    let sent = tx.send(post).await;
-                    if sent.is_err() {
-                        panic!("Channel sender poisoned");
-                    }
+            if listing.seek() == Seek::Older && listing.paginator().is_none_or(|p| p.is_exhausted())
+            {
+                info!("Finished polling back, no more posts. Exiting ...");
+                break;
+            }
+            sleep_until(Instant::now() + interval).await;
+        }
+    }
+
+    fn live_feeds(&self) -> u32 {
+        let feeds = self.feeds.lock().unwrap();
+        feeds
+            .values()
+            .filter(|feed| feed.strong_count() > 0)
+            .count() as u32
+    }
+
+    /// How each live listing's feed is doing, busiest first.
+    pub fn polling(&self) -> Vec<PollStatus> {
+        let feeds = self.feeds.lock().unwrap();
+        let mut polling = feeds
+            .values()
+            .filter_map(Weak::upgrade)
+            .map(|feed| {
+                let schedule = feed.schedule.lock().unwrap();
+                PollStatus {
+                    listing: format!("{}/{}", feed.key.target, feed.key.category),
+                    interval: schedule.interval(),
+                    mean_gap: schedule.mean_gap(),
+                    health: feed.health.lock().unwrap().clone(),
                 }
+            })
+            .collect::<Vec<_>>();
+        polling.sort_by_key(|status| status.interval);
+        polling
+    }
 
-                sync_interval = SYNC_INTERVAL_DEFAULT;
-                continue;
-            } else {
-                if sync_interval < SYNC_INTERVAL_MAX {
-                    sync_interval *= 2;
+    /// The key under which `listing`'s feed keeps its cursor and seen posts.
+    pub fn feed_key(&self, listing: &Listing) -> FeedKey {
+        FeedKey::from(self.src.name(), listing)
+    }
+
+    /// Retrieves the listing's next page outside of any feed, e.g. for a
+    /// random post on demand or a backfill.
+    pub async fn fetch(&self, listing: &mut Listing) -> Result<VecDeque<Post>, SourceError> {
+        self.src.clone().retrieve_posts(listing).await
+    }
+
+    /// Picks a random post from the listing every `every`.
+    async fn random_listener(
+        mut api: T,
+        tx: Sender<FeedEvent>,
+        mut listing: Listing,
+        every: Duration,
+    ) {
+        let sub = listing.subreddit();
+        loop {
+            match api.retrieve_posts(&mut listing).await {
+                Ok(posts) => {
+                    info!("Picked {} random post(s) from `{}`", posts.len(), sub);
+                    for post in posts {
+                        let _ = tx.send(FeedEvent::Post(post));
+                    }
                 }
-                info!(
-                    "No new post since last poll for `r/{}`, \
-                         increased wait interval to {}s",
-                    sub.name(),
-                    sync_interval
-                );
+                Err(e) => {
+                    if Self::recover(e, &sub, &tx).await {
+                        continue;
+                    }
+                    break;
+                }
+            }
+            sleep_until(Instant::now() + every).await;
+        }
+    }
+
+    /// Waits as long as the failed retrieval calls for, returning `false`
+    /// when the listing is gone and subscribers were told so.
+    async fn recover(e: SourceError, sub: &Subreddit, tx: &Sender<FeedEvent>) -> bool {
+        let wait = match &e {
+            SourceError::RateLimited { retry_after } => {
+                warn!("Rate limited while polling `{}`", sub);
+                *retry_after
+            }
+            e if e.is_gone() => {
+                error!("Stopped polling `{}`: {}", sub, e);
+                let _ = tx.send(FeedEvent::Gone(e.to_string()));
+                return false;
             }
+            SourceError::Auth(_) => {
+                error!("couldn't authenticate to poll `{}`: {}", sub, e);
+                Duration::from_secs(AUTH_RETRY_INTERVAL)
+            }
+            _ => {
+                error!("couldn't retrieve posts: {}", e);
+                Duration::from_secs(RETRY_INTERVAL)
+            }
+        };
+        warn!("Retrying post retrieval in {}s", wait.as_secs());
+        sleep_until(Instant::now() + wait).await;
+        true
+    }
+}
 
-            sleep_until(Instant::now() + Duration::from_secs(sync_interval)).await;
-            if sync_interval >= SYNC_INTERVAL_MAX {
-                if timeout_cnt == 2 {
-                    let mut synced_posts = VecDeque::new();
-                    {
-                        info!("Polling timeout for `r/{}`, retrying ...", sub.name());
-                        let mut listing_guard = listing.lock().await;
-                        match listing_guard.paginator().cursor() {
-                            Seek::After { .. } => {
-                                let mut deck = VecDeque::new();
-                                deck.push_back(Post::empty());
-                                listing_guard.update_paginator_cache(&deck);
-
-                                let mut res = api.retrieve_posts(&mut listing_guard).await.unwrap();
-                                synced_posts.append(&mut res);
-                            }
-                            Seek::Back { .. } => {
-                                info!("Finished polling back, no more posts. Exiting ...");
-                                break;
-                            }
-                        }
-                    }
+#[tokio::test]
+async fn test_shared_feed() {
+    use crate::testing::{post, MockSource, TestDb};
 
-                    let new_posts = buffer.difference(synced_posts);
-                    for post in new_posts {
-                        buffer.insert(post.clone());
-                        let sent = tx.send(post).await;
-                        if sent.is_err() {
-                            panic!("Channel sender poisoned");
-                        }
-                    }
+    let db = TestDb::new();
+    let src = MockSource::default();
+    src.push_page("Art", vec![post("a"), post("b")]);
+    src.remove("Gone");
+    let curator = Curator::from(src.clone(), &db.pool);
 
-                    sync_interval = SYNC_INTERVAL_DEFAULT;
-                    timeout_cnt = 0;
-                } else {
-                    timeout_cnt += 1;
-                }
+    let listing = Listing::parse("new", "Art".into()).unwrap();
+    let mut first = curator.subscribe(listing.clone());
+    let mut second = curator.subscribe(listing);
+    for feed in [&mut first, &mut second] {
+        for id in ["a", "b"] {
+            match timeout(Duration::from_secs(5), feed.recv()).await {
+                Ok(Some(FeedEvent::Post(post))) => assert_eq!(post.id(), id),
+                event => panic!("expected post `{}`, got {:?}", id, event),
             }
         }
     }
+    assert_eq!(src.requests(), 1);
+    assert_eq!(curator.polling().len(), 1);
+
+    let mut gone = curator.subscribe(Listing::parse("new", "Gone".into()).unwrap());
+    let event = timeout(Duration::from_secs(5), gone.recv()).await.unwrap();
+    assert!(matches!(event, Some(FeedEvent::Gone(_))));
+
+    curator.shutdown().await;
 }
@@ -0,0 +1,410 @@
+use std::fmt::{Display, Formatter};
+
+use chrono::{DateTime, Utc};
+
+use diesel::connection::SimpleConnection;
+use diesel::migration::{Migration, MigrationSource, MigrationVersion};
+use diesel::pg::{Pg, PgConnection};
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Pool, PooledConnection};
+use diesel::result::DatabaseErrorKind;
+use diesel::sqlite::{Sqlite, SqliteConnection};
+use diesel::{AsExpression, QueryResult};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use log::{error, info};
+use tokio::task::spawn_blocking;
+
+use crate::aggregator::{AggregatorStore, SqlAggregatorStore};
+use crate::artvault::{ArtVault, SqlArtVault};
+use crate::auth::{ClientManager, SqlClientManager};
+
+/// Every migration under `migrations/`, built into the binary.
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+/// The same schema as `MIGRATIONS`, written for SQLite.
+pub const SQLITE_MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations_sqlite");
+
+// Repositories check a connection out for each query, so this only bounds
+// how many queries run at once.
+const POOL_SIZE: u32 = 16;
+
+// How long a SQLite connection waits for another one's write to finish.
+const SQLITE_BUSY_TIMEOUT_MS: u32 = 5000;
+
+/// The database the bot keeps its state in, picked by the scheme of
+/// `DATABASE_URL`.
+#[derive(Clone)]
+pub enum DbPool {
+    Postgres(Pool<ConnectionManager<PgConnection>>),
+    Sqlite(Pool<ConnectionManager<SqliteConnection>>),
+}
+
+pub enum DbConn {
+    Postgres(PooledConnection<ConnectionManager<PgConnection>>),
+    Sqlite(PooledConnection<ConnectionManager<SqliteConnection>>),
+}
+
+/// Runs a diesel query against whichever backend `$db`, a `DbPool`, is
+/// connected to, as in `with_conn!(self.db, |conn| query.execute(conn))`. The
+/// body is compiled once per backend, so it must only use SQL both understand,
+/// and returns a `QueryResult` so a connection that couldn't be had fails it.
+macro_rules! with_conn {
+    ($db:expr, |$conn:ident| $body:expr) => {
+        match $crate::db::conn(&$db) {
+            Ok($crate::db::DbConn::Postgres(mut pooled)) => {
+                let $conn = &mut pooled;
+                $body
+            }
+            Ok($crate::db::DbConn::Sqlite(mut pooled)) => {
+                let $conn = &mut pooled;
+                $body
+            }
+            Err(e) => Err(e),
+        }
+    };
+}
+
+pub(crate) use with_conn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    Postgres,
+    Sqlite,
+}
+
+/// Which backend `url` points to, and what to hand its driver. SQLite
+/// databases are given as `sqlite://<path>`, `sqlite::memory:` for one that
+/// lives as long as its connection, or a `file:` URI.
+fn backend(url: &str) -> Option<(Backend, &str)> {
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        return Some((Backend::Postgres, url));
+    }
+    if let Some(path) = url.strip_prefix("sqlite://") {
+        return Some((Backend::Sqlite, path));
+    }
+    if let Some(path) = url.strip_prefix("sqlite:") {
+        return Some((Backend::Sqlite, path));
+    }
+    if url.starts_with("file:") {
+        return Some((Backend::Sqlite, url));
+    }
+    None
+}
+
+// SQLite only lets one connection write at a time; waiting on the lock
+// rather than failing right away lets the pool's connections take turns.
+#[derive(Debug)]
+struct SqliteSetup;
+
+impl CustomizeConnection<SqliteConnection, diesel::r2d2::Error> for SqliteSetup {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
+        conn.batch_execute(&format!(
+            "PRAGMA busy_timeout = {}; PRAGMA journal_mode = WAL; PRAGMA foreign_keys = ON;",
+            SQLITE_BUSY_TIMEOUT_MS
+        ))
+        .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
+/// Whether `database_url` points to a backend the bot can store its data in.
+pub fn supports(database_url: &str) -> bool {
+    backend(database_url).is_some()
+}
+
+/// Connects to `database_url`, shared by every repository of the bot.
+pub fn connect(database_url: &str) -> DbPool {
+    let pool = match backend(database_url) {
+        Some((Backend::Postgres, url)) => Pool::builder()
+            .max_size(POOL_SIZE)
+            .build(ConnectionManager::new(url))
+            .map(DbPool::Postgres),
+        Some((Backend::Sqlite, path)) => Pool::builder()
+            .max_size(POOL_SIZE)
+            .connection_customizer(Box::new(SqliteSetup))
+            .build(ConnectionManager::new(path))
+            .map(DbPool::Sqlite),
+        None => panic!(
+            "DATABASE_URL must start with postgres://, postgresql:// or sqlite:, got {}",
+            database_url
+        ),
+    };
+    pool.unwrap_or_else(|e| panic!("Error connecting to {}: {}", database_url, e))
+}
+
+/// A connection out of `pool`, waiting for one to be returned if needed.
+/// Fails like a query would when none is returned in time.
+pub fn conn(pool: &DbPool) -> QueryResult<DbConn> {
+    let conn = match pool {
+        DbPool::Postgres(pool) => pool.get().map(DbConn::Postgres),
+        DbPool::Sqlite(pool) => pool.get().map(DbConn::Sqlite),
+    };
+    conn.map_err(|e| {
+        error!("couldn't get a database connection: {}", e);
+        diesel::result::Error::DatabaseError(
+            DatabaseErrorKind::UnableToSendCommand,
+            Box::new(e.to_string()),
+        )
+    })
+}
+
+/// Runs synchronous database work off the async runtime's threads.
+pub async fn blocking<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    spawn_blocking(f).await.expect("database task panicked")
+}
+
+/// Hands out the repositories that don't have to be backed by a database.
+pub trait Storage: Send + Sync {
+    fn vault(&self) -> Box<dyn ArtVault>;
+    fn clients(&self) -> Box<dyn ClientManager>;
+    fn subscriptions(&self) -> Box<dyn AggregatorStore>;
+}
+
+impl Storage for DbPool {
+    fn vault(&self) -> Box<dyn ArtVault> {
+        Box::new(SqlArtVault::from(self))
+    }
+
+    fn clients(&self) -> Box<dyn ClientManager> {
+        Box::new(SqlClientManager::from(self))
+    }
+
+    fn subscriptions(&self) -> Box<dyn AggregatorStore> {
+        Box::new(SqlAggregatorStore::from(self))
+    }
+}
+
+/// A point in time as it's bound in queries. `DateTime<Utc>` can't be bound
+/// to `sql_types::Timestamptz` directly, as neither type is this crate's.
+#[derive(AsExpression, Debug, Clone, Copy, PartialEq)]
+#[diesel(sql_type = sql_types::Timestamptz)]
+pub struct UtcTime(pub DateTime<Utc>);
+
+/// Column types that map to a different native type on each backend.
+pub mod sql_types {
+    use chrono::{DateTime, Utc};
+    use diesel::deserialize::{self, FromSql};
+    use diesel::pg::Pg;
+    use diesel::serialize::{self, Output, ToSql};
+    use diesel::sql_types::{self, ops, Interval};
+    use diesel::sqlite::Sqlite;
+    use diesel::{QueryId, SqlType};
+
+    use super::UtcTime;
+
+    /// A point in time: `TIMESTAMPTZ` on Postgres, and on SQLite the text
+    /// diesel writes for its own timestamps with a UTC offset, which sorts
+    /// the same as the times do.
+    #[derive(SqlType, QueryId, Debug, Clone, Copy)]
+    #[diesel(postgres_type(name = "timestamptz"))]
+    #[diesel(sqlite_type(name = "Text"))]
+    pub struct Timestamptz;
+
+    impl ops::Add for Timestamptz {
+        type Rhs = Interval;
+        type Output = Timestamptz;
+    }
+
+    impl ops::Sub for Timestamptz {
+        type Rhs = Interval;
+        type Output = Timestamptz;
+    }
+
+    impl ToSql<Timestamptz, Pg> for UtcTime {
+        fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+            ToSql::<sql_types::Timestamptz, Pg>::to_sql(&self.0, out)
+        }
+    }
+
+    impl ToSql<Timestamptz, Sqlite> for UtcTime {
+        fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Sqlite>) -> serialize::Result {
+            ToSql::<sql_types::TimestamptzSqlite, Sqlite>::to_sql(&self.0, out)
+        }
+    }
+
+    impl FromSql<Timestamptz, Pg> for DateTime<Utc> {
+        fn from_sql(
+            value: <Pg as diesel::backend::Backend>::RawValue<'_>,
+        ) -> deserialize::Result<Self> {
+            FromSql::<sql_types::Timestamptz, Pg>::from_sql(value)
+        }
+    }
+
+    impl FromSql<Timestamptz, Sqlite> for DateTime<Utc> {
+        fn from_sql(
+            value: <Sqlite as diesel::backend::Backend>::RawValue<'_>,
+        ) -> deserialize::Result<Self> {
+            FromSql::<sql_types::TimestamptzSqlite, Sqlite>::from_sql(value)
+        }
+    }
+}
+
+/// Why the schema couldn't be brought up to date.
+#[derive(Debug)]
+pub enum MigrationError {
+    /// The database has migrations this binary doesn't know about, i.e. it
+    /// was migrated by a newer version of the bot.
+    Ahead(Vec<String>),
+    Failed(String),
+}
+
+impl Display for MigrationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MigrationError::Ahead(versions) => write!(
+                f,
+                "the database is ahead of this binary, unknown migration(s): {}",
+                versions.join(", ")
+            ),
+            MigrationError::Failed(e) => write!(f, "couldn't run migrations: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+/// Applies the pending migrations, returning how many ran. Refuses to touch
+/// a database that's already ahead of the binary.
+pub fn migrate(pool: &DbPool) -> Result<usize, MigrationError> {
+    match pool {
+        DbPool::Postgres(pool) => {
+            let mut db = pool
+                .get()
+                .map_err(|e| MigrationError::Failed(e.to_string()))?;
+            run_migrations::<Pg>(&mut *db, MIGRATIONS)
+        }
+        DbPool::Sqlite(pool) => {
+            let mut db = pool
+                .get()
+                .map_err(|e| MigrationError::Failed(e.to_string()))?;
+            run_migrations::<Sqlite>(&mut *db, SQLITE_MIGRATIONS)
+        }
+    }
+}
+
+fn run_migrations<DB: diesel::backend::Backend>(
+    db: &mut impl MigrationHarness<DB>,
+    migrations: EmbeddedMigrations,
+) -> Result<usize, MigrationError> {
+    let known = MigrationSource::<DB>::migrations(&migrations)
+        .map_err(|e| MigrationError::Failed(e.to_string()))?
+        .iter()
+        .map(|m| m.name().version().as_owned())
+        .collect::<Vec<_>>();
+    let applied = db
+        .applied_migrations()
+        .map_err(|e| MigrationError::Failed(e.to_string()))?;
+    let unknown = unknown_versions(&known, &applied);
+    if !unknown.is_empty() {
+        return Err(MigrationError::Ahead(unknown));
+    }
+
+    let ran = db
+        .run_pending_migrations(migrations)
+        .map_err(|e| MigrationError::Failed(e.to_string()))?;
+    for version in ran.iter() {
+        info!("Applied migration {}", version);
+    }
+    Ok(ran.len())
+}
+
+fn unknown_versions(
+    known: &[MigrationVersion<'static>],
+    applied: &[MigrationVersion<'static>],
+) -> Vec<String> {
+    applied
+        .iter()
+        .filter(|version| !known.contains(version))
+        .map(|version| version.to_string())
+        .collect()
+}
+
+#[test]
+fn test_unknown_migrations() {
+    let known = MigrationSource::<Pg>::migrations(&MIGRATIONS)
+        .unwrap()
+        .iter()
+        .map(|m| m.name().version().as_owned())
+        .collect::<Vec<_>>();
+    assert!(known.contains(&MigrationVersion::from("20230528090000")));
+
+    let mut applied = known.iter().map(|v| v.as_owned()).collect::<Vec<_>>();
+    assert!(unknown_versions(&known, &applied).is_empty());
+    applied.push(MigrationVersion::from("20991231000000"));
+    assert_eq!(
+        unknown_versions(&known, &applied),
+        vec!["20991231000000".to_string()]
+    );
+}
+
+#[test]
+fn test_database_url() {
+    assert_eq!(
+        backend("postgres://bot@localhost/artbutler"),
+        Some((Backend::Postgres, "postgres://bot@localhost/artbutler"))
+    );
+    assert_eq!(
+        backend("sqlite:///var/lib/artbutler.db"),
+        Some((Backend::Sqlite, "/var/lib/artbutler.db"))
+    );
+    assert_eq!(
+        backend("sqlite::memory:"),
+        Some((Backend::Sqlite, ":memory:"))
+    );
+    assert_eq!(backend("mysql://localhost/artbutler"), None);
+
+    // Both backends know every migration, under the same versions.
+    let versions = |migrations| {
+        MigrationSource::<Sqlite>::migrations(&migrations)
+            .unwrap()
+            .iter()
+            .map(|m| m.name().version().as_owned())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(versions(MIGRATIONS), versions(SQLITE_MIGRATIONS));
+}
+
+#[test]
+fn test_sqlite_storage() {
+    use crate::auth::{BotClient, ClientID};
+    use crate::content::Post;
+    use crate::listings::reddit::Listing;
+
+    let db = crate::testing::TestDb::new();
+    let pool = &db.pool;
+    assert_eq!(migrate(pool).unwrap(), 0);
+
+    let posted_at = Utc::now();
+    let post = Post::new(
+        "t3_sqlite".to_string(),
+        "https://i.redd.it/sqlite.png".to_string(),
+        "author".to_string(),
+        "title".to_string(),
+        (1, 0),
+    )
+    .posted(posted_at);
+    let mut vault = pool.vault();
+    vault.save(&post);
+    vault.save(&post);
+    assert_eq!(vault.fetch(post.id()).unwrap().posted_at, Some(posted_at));
+    vault.save_file_id(&post.media_href, "first");
+    vault.save_file_id(&post.media_href, "second");
+    assert_eq!(vault.file_id(&post.media_href), Some("second".to_string()));
+
+    let client = ClientID::from(42);
+    pool.clients().add(BotClient {
+        id: client,
+        username: None,
+        is_user: true,
+    });
+    assert!(pool.clients().get(client).is_some());
+
+    let listing = Listing::parse("new", "sqlite".into()).unwrap();
+    let mut subscriptions = pool.subscriptions();
+    assert_eq!(subscriptions.subscribe(client, &listing).unwrap(), 1);
+    assert_eq!(subscriptions.subscribe(client, &listing).unwrap(), 0);
+    assert_eq!(subscriptions.listings(client).unwrap().len(), 1);
+    assert_eq!(subscriptions.all().unwrap(), vec![(client, listing)]);
+}
@@ -0,0 +1,805 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Timelike, Utc};
+use chrono_tz::Tz;
+use diesel::prelude::*;
+use log::{error, info, warn};
+use teloxide::types::ChatId;
+use tokio::sync::Mutex;
+use tokio::time::{sleep_until, Instant};
+
+use crate::auth::ClientID;
+use crate::content::{Post, SubscribedListing};
+use crate::db::{self, with_conn, DbPool, UtcTime};
+use crate::delivery::DeliveryMode::{Daily, Hourly, Realtime, Top, Weekly};
+use crate::listings::reddit::{Listing, Subreddit};
+use crate::outbound::Outbox;
+use crate::retry::DeliveryQueue;
+use crate::schema::{artposts, chat_settings, queued_posts};
+
+pub const DIGEST_CHECK_INTERVAL: u64 = 60;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DeliveryMode {
+    Realtime,
+    Hourly,
+    Daily,
+    /// Everything collected over a week, best first, e.g. for `top/week`
+    /// listings whose posts are each delivered once.
+    Weekly,
+    Top(u32),
+}
+
+impl DeliveryMode {
+    pub fn from(mode: &str, size: Option<i32>) -> Option<DeliveryMode> {
+        match mode {
+            "realtime" => Some(Realtime),
+            "hourly" => Some(Hourly),
+            "daily" => Some(Daily),
+            "weekly" => Some(Weekly),
+            "top" => match size {
+                Some(n) if n > 0 => Some(Top(n as u32)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn tag(&self) -> &'static str {
+        match self {
+            Realtime => "realtime",
+            Hourly => "hourly",
+            Daily => "daily",
+            Weekly => "weekly",
+            Top(_) => "top",
+        }
+    }
+
+    pub fn digest_size(&self) -> Option<i32> {
+        match self {
+            Top(n) => Some(*n as i32),
+            _ => None,
+        }
+    }
+
+    /// Whether digests of this mode put the highest scoring posts first.
+    pub fn by_score(&self) -> bool {
+        matches!(self, Weekly | Top(_))
+    }
+
+    /// Whether a digest for this mode should go out at `now`, given when the
+    /// previous one was sent and the chat's schedule. Daily digests catch up
+    /// on the first check past the scheduled hour, so a missed tick only
+    /// delays them.
+    pub fn is_due(
+        &self,
+        last_digest: Option<DateTime<Utc>>,
+        now: DateTime<Utc>,
+        schedule: &DigestSchedule,
+    ) -> bool {
+        match self {
+            Realtime => false,
+            Hourly => match last_digest {
+                Some(last) => now - last >= chrono::Duration::hours(1),
+                None => true,
+            },
+            Daily | Weekly | Top(_) => {
+                let local_now = now.with_timezone(&schedule.timezone);
+                if local_now.hour() < schedule.hour {
+                    return false;
+                }
+                let days = if *self == Weekly { 7 } else { 1 };
+                match last_digest {
+                    Some(last) => {
+                        let last = last.with_timezone(&schedule.timezone).date_naive();
+                        local_now.date_naive() - last >= chrono::Duration::days(days)
+                    }
+                    None => true,
+                }
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for DeliveryMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Top(n) => write!(f, "top {}", n),
+            mode => f.write_str(mode.tag()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DigestSchedule {
+    pub timezone: Tz,
+    pub hour: u32,
+}
+
+impl Default for DigestSchedule {
+    fn default() -> Self {
+        Self {
+            timezone: Tz::UTC,
+            hour: 9,
+        }
+    }
+}
+
+impl DigestSchedule {
+    pub fn from(hour: &str, timezone: Option<&str>) -> Option<DigestSchedule> {
+        let hour = hour.parse::<u32>().ok().filter(|h| *h < 24)?;
+        let timezone = match timezone {
+            Some(name) => name.parse::<Tz>().ok()?,
+            None => Tz::UTC,
+        };
+        Some(Self { timezone, hour })
+    }
+}
+
+/// What happens to posts that arrive during quiet hours or after a chat has
+/// used up its hourly budget.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Hold every post and deliver them, oldest first, as the budget allows.
+    Queue,
+    /// Deliver only a budget's worth of held posts, discarding the lowest
+    /// scored.
+    DropLowest,
+    /// Hold every post and deliver them together with the chat's next
+    /// daily digest.
+    Digest,
+}
+
+impl OverflowPolicy {
+    pub fn from(policy: &str) -> Option<OverflowPolicy> {
+        match policy {
+            "queue" => Some(OverflowPolicy::Queue),
+            "drop" => Some(OverflowPolicy::DropLowest),
+            "digest" => Some(OverflowPolicy::Digest),
+            _ => None,
+        }
+    }
+
+    pub fn tag(&self) -> &'static str {
+        match self {
+            OverflowPolicy::Queue => "queue",
+            OverflowPolicy::DropLowest => "drop",
+            OverflowPolicy::Digest => "digest",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatLimits {
+    pub timezone: Tz,
+    pub quiet_hours: Option<(u32, u32)>,
+    pub hourly_budget: Option<u32>,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for ChatLimits {
+    fn default() -> Self {
+        Self {
+            timezone: Tz::UTC,
+            quiet_hours: None,
+            hourly_budget: None,
+            overflow: OverflowPolicy::Queue,
+        }
+    }
+}
+
+impl ChatLimits {
+    /// Quiet hours run from the start hour up to, but excluding, the end hour
+    /// in the chat's timezone and may wrap past midnight, e.g. `22..7`.
+    pub fn is_quiet(&self, now: DateTime<Utc>) -> bool {
+        let (start, end) = match self.quiet_hours {
+            Some(hours) => hours,
+            None => return false,
+        };
+        let hour = now.with_timezone(&self.timezone).hour();
+        if start <= end {
+            start <= hour && hour < end
+        } else {
+            hour >= start || hour < end
+        }
+    }
+}
+
+/// Tracks what was sent to each chat during the last hour, so a chat's budget
+/// holds across all of its subscriptions.
+#[derive(Default)]
+pub struct DeliveryThrottle {
+    sent: HashMap<i64, VecDeque<DateTime<Utc>>>,
+}
+
+impl DeliveryThrottle {
+    /// Posts the chat can still receive this hour, `None` when unlimited.
+    pub fn remaining(
+        &mut self,
+        chat: ClientID,
+        limits: &ChatLimits,
+        now: DateTime<Utc>,
+    ) -> Option<u32> {
+        let budget = limits.hourly_budget?;
+        let sent = self.expire(chat, now);
+        Some(budget.saturating_sub(sent.len() as u32))
+    }
+
+    /// Reserves a slot for one post. Returns `false` if the chat is in its
+    /// quiet hours or out of budget, in which case nothing is reserved.
+    pub fn try_acquire(&mut self, chat: ClientID, limits: &ChatLimits, now: DateTime<Utc>) -> bool {
+        if limits.is_quiet(now) || self.remaining(chat, limits, now) == Some(0) {
+            return false;
+        }
+        self.expire(chat, now).push_back(now);
+        true
+    }
+
+    fn expire(&mut self, chat: ClientID, now: DateTime<Utc>) -> &mut VecDeque<DateTime<Utc>> {
+        let sent = self.sent.entry(chat.id()).or_default();
+        while let Some(at) = sent.front() {
+            if now - *at < chrono::Duration::hours(1) {
+                break;
+            }
+            sent.pop_front();
+        }
+        sent
+    }
+}
+
+#[derive(Queryable)]
+struct ChatSettings {
+    _chat_id: i64,
+    timezone: String,
+    digest_hour: i16,
+    quiet_start: Option<i16>,
+    quiet_end: Option<i16>,
+    hourly_budget: Option<i32>,
+    overflow_policy: String,
+}
+
+impl ChatSettings {
+    fn timezone(&self) -> Tz {
+        self.timezone.parse().unwrap_or(Tz::UTC)
+    }
+}
+
+#[derive(Insertable, AsChangeset)]
+#[diesel(table_name = chat_settings)]
+struct NewDigestSettings {
+    chat_id: i64,
+    timezone: String,
+    digest_hour: i16,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = queued_posts)]
+struct NewQueuedPost {
+    chat_id: i64,
+    subreddit: String,
+    category: String,
+    post_id: String,
+    score: i32,
+}
+
+/// Posts held back for digests or by a chat's delivery limits, together with
+/// each chat's digest schedule and limits.
+pub struct DigestQueue {
+    db: DbPool,
+}
+
+impl DigestQueue {
+    pub fn from(pool: &DbPool) -> Self {
+        Self { db: pool.clone() }
+    }
+
+    /// Queues a post for the next digest of `listing`, or holds it back until
+    /// the chat's limits allow it through. The post must already be stored in
+    /// the `ArtVault`.
+    pub fn enqueue(&mut self, chat: ClientID, listing: &Listing, post: &Post) {
+        let queued = NewQueuedPost {
+            chat_id: chat.id(),
+            subreddit: listing.subreddit().name(),
+            category: listing.category(),
+            post_id: post.id().to_string(),
+            score: post.score(),
+        };
+
+        let res = with_conn!(self.db, |conn| diesel::insert_into(queued_posts::table)
+            .values(&queued)
+            .on_conflict_do_nothing()
+            .execute(conn));
+        if let Err(e) = res {
+            error!("couldn't queue PostID \"{}\": {}", post.id(), e);
+        }
+    }
+
+    pub fn schedule(&mut self, chat: ClientID) -> DigestSchedule {
+        match self.settings(chat) {
+            Some(settings) => DigestSchedule {
+                timezone: settings.timezone(),
+                hour: settings.digest_hour as u32,
+            },
+            None => DigestSchedule::default(),
+        }
+    }
+
+    pub fn limits(&mut self, chat: ClientID) -> ChatLimits {
+        match self.settings(chat) {
+            Some(settings) => ChatLimits {
+                timezone: settings.timezone(),
+                quiet_hours: match (settings.quiet_start, settings.quiet_end) {
+                    (Some(start), Some(end)) => Some((start as u32, end as u32)),
+                    _ => None,
+                },
+                hourly_budget: settings.hourly_budget.map(|n| n as u32),
+                overflow: OverflowPolicy::from(settings.overflow_policy.as_str())
+                    .unwrap_or(OverflowPolicy::Queue),
+            },
+            None => ChatLimits::default(),
+        }
+    }
+
+    fn settings(&mut self, chat: ClientID) -> Option<ChatSettings> {
+        use crate::schema::chat_settings::dsl::*;
+
+        with_conn!(self.db, |conn| chat_settings
+            .find(chat.id())
+            .get_result::<ChatSettings>(conn))
+        .ok()
+    }
+
+    pub fn set_schedule(
+        &mut self,
+        chat: ClientID,
+        schedule: &DigestSchedule,
+    ) -> QueryResult<usize> {
+        let settings = NewDigestSettings {
+            chat_id: chat.id(),
+            timezone: schedule.timezone.name().to_string(),
+            digest_hour: schedule.hour as i16,
+        };
+
+        with_conn!(self.db, |conn| diesel::insert_into(chat_settings::table)
+            .values(&settings)
+            .on_conflict(chat_settings::chat_id)
+            .do_update()
+            .set(&settings)
+            .execute(conn))
+    }
+
+    /// Sets or, with `None`, lifts the chat's quiet hours. A `timezone`
+    /// replaces the one the chat's digests are scheduled in as well.
+    pub fn set_quiet_hours(
+        &mut self,
+        chat: ClientID,
+        hours: Option<(u32, u32)>,
+        timezone: Option<Tz>,
+    ) -> QueryResult<usize> {
+        use crate::schema::chat_settings::dsl;
+
+        let start = hours.map(|(start, _)| start as i16);
+        let end = hours.map(|(_, end)| end as i16);
+        with_conn!(self.db, |conn| match timezone {
+            Some(tz) => diesel::insert_into(dsl::chat_settings)
+                .values((
+                    dsl::chat_id.eq(chat.id()),
+                    dsl::quiet_start.eq(start),
+                    dsl::quiet_end.eq(end),
+                    dsl::timezone.eq(tz.name()),
+                ))
+                .on_conflict(dsl::chat_id)
+                .do_update()
+                .set((
+                    dsl::quiet_start.eq(start),
+                    dsl::quiet_end.eq(end),
+                    dsl::timezone.eq(tz.name()),
+                ))
+                .execute(conn),
+            None => diesel::insert_into(dsl::chat_settings)
+                .values((
+                    dsl::chat_id.eq(chat.id()),
+                    dsl::quiet_start.eq(start),
+                    dsl::quiet_end.eq(end),
+                ))
+                .on_conflict(dsl::chat_id)
+                .do_update()
+                .set((dsl::quiet_start.eq(start), dsl::quiet_end.eq(end)))
+                .execute(conn),
+        })
+    }
+
+    /// Sets or, with `None`, lifts the chat's hourly budget.
+    pub fn set_budget(
+        &mut self,
+        chat: ClientID,
+        budget: Option<u32>,
+        overflow: OverflowPolicy,
+    ) -> QueryResult<usize> {
+        use crate::schema::chat_settings::dsl;
+
+        let budget = budget.map(|n| n as i32);
+        with_conn!(self.db, |conn| diesel::insert_into(dsl::chat_settings)
+            .values((
+                dsl::chat_id.eq(chat.id()),
+                dsl::hourly_budget.eq(budget),
+                dsl::overflow_policy.eq(overflow.tag()),
+            ))
+            .on_conflict(dsl::chat_id)
+            .do_update()
+            .set((
+                dsl::hourly_budget.eq(budget),
+                dsl::overflow_policy.eq(overflow.tag()),
+            ))
+            .execute(conn))
+    }
+
+    fn subscriptions(&mut self) -> Vec<SubscribedListing> {
+        use crate::schema::subscribed_listings::dsl::*;
+
+        with_conn!(self.db, |conn| subscribed_listings
+            .load::<SubscribedListing>(conn))
+        .unwrap_or_else(|e| {
+            error!("error loading subscriptions: {}", e);
+            vec![]
+        })
+    }
+
+    /// Posts queued for `sub`, oldest first or, when `by_score`, highest
+    /// scored first.
+    fn queued(&mut self, sub: &SubscribedListing, by_score: bool, limit: Option<i64>) -> Vec<Post> {
+        use crate::schema::queued_posts::dsl::*;
+
+        let posts = with_conn!(self.db, |conn| {
+            let mut query = queued_posts
+                .inner_join(artposts::table)
+                .filter(chat_id.eq(sub.user_id))
+                .filter(subreddit.eq(&sub.subreddit))
+                .filter(category.eq(&sub.category))
+                .select(artposts::all_columns)
+                .into_boxed();
+            query = if by_score {
+                query.order(score.desc())
+            } else {
+                query.order(queued_at.asc())
+            };
+            if let Some(n) = limit {
+                query = query.limit(n);
+            }
+            query.load::<Post>(conn)
+        });
+        posts.unwrap_or_else(|e| {
+            error!("error loading queued posts: {}", e);
+            vec![]
+        })
+    }
+
+    /// Drops all but the `keep` highest scored posts queued for `sub`.
+    fn trim(&mut self, sub: &SubscribedListing, keep: usize) {
+        use crate::schema::queued_posts::dsl::*;
+
+        let dropped = self
+            .queued(sub, true, None)
+            .into_iter()
+            .skip(keep)
+            .map(|post| post.id)
+            .collect::<Vec<_>>();
+        if dropped.is_empty() {
+            return;
+        }
+
+        let res = with_conn!(self.db, |conn| diesel::delete(
+            queued_posts
+                .filter(chat_id.eq(sub.user_id))
+                .filter(post_id.eq_any(&dropped)),
+        )
+        .execute(conn));
+        match res {
+            Ok(n) => info!(
+                "Dropped {} lowest scored post(s) held for ChatID: '{}'",
+                n, sub.user_id
+            ),
+            Err(e) => error!("couldn't trim held posts: {}", e),
+        }
+    }
+
+    fn remove(&mut self, chat: ClientID, post: &Post) {
+        use crate::schema::queued_posts::dsl::*;
+
+        let res = with_conn!(self.db, |conn| diesel::delete(
+            queued_posts.find((chat.id(), post.id()))
+        )
+        .execute(conn));
+        if let Err(e) = res {
+            error!("couldn't dequeue PostID \"{}\": {}", post.id(), e);
+        }
+    }
+
+    fn clear(&mut self, sub: &SubscribedListing) {
+        use crate::schema::queued_posts::dsl::*;
+
+        let res = with_conn!(self.db, |conn| diesel::delete(
+            queued_posts
+                .filter(chat_id.eq(sub.user_id))
+                .filter(subreddit.eq(&sub.subreddit))
+                .filter(category.eq(&sub.category)),
+        )
+        .execute(conn));
+        if let Err(e) = res {
+            error!("couldn't clear digest queue: {}", e);
+        }
+    }
+
+    fn mark_digested(&mut self, sub: &SubscribedListing, digested_at: DateTime<Utc>) {
+        use crate::schema::subscribed_listings::dsl::*;
+
+        let res = with_conn!(self.db, |conn| diesel::update(subscribed_listings.find((
+            sub.user_id,
+            sub.subreddit.to_string(),
+            sub.category.to_string(),
+        )))
+        .set(last_digest_at.eq(Some(UtcTime(digested_at))))
+        .execute(conn));
+        if let Err(e) = res {
+            error!("couldn't update digest timestamp: {}", e);
+        }
+    }
+}
+
+/// Periodically batches queued posts into media groups for every
+/// subscription whose digest is due, and releases posts held back by a
+/// chat's quiet hours or budget to the `DeliveryQueue` once its limits allow.
+pub struct DigestScheduler {
+    outbox: Outbox,
+    db: DbPool,
+    throttle: Arc<Mutex<DeliveryThrottle>>,
+}
+
+impl DigestScheduler {
+    pub fn from(outbox: Outbox, throttle: Arc<Mutex<DeliveryThrottle>>, pool: &DbPool) -> Self {
+        Self {
+            outbox,
+            db: pool.clone(),
+            throttle,
+        }
+    }
+
+    pub async fn run(mut self) {
+        loop {
+            self.deliver_due(Utc::now()).await;
+            sleep_until(Instant::now() + Duration::from_secs(DIGEST_CHECK_INTERVAL)).await;
+        }
+    }
+
+    async fn deliver_due(&mut self, now: DateTime<Utc>) {
+        let pool = self.db.clone();
+        let subscriptions = db::blocking(move || {
+            let mut queue = DigestQueue::from(&pool);
+            queue
+                .subscriptions()
+                .into_iter()
+                .map(|sub| {
+                    let limits = queue.limits(sub.user_id.into());
+                    let schedule = queue.schedule(sub.user_id.into());
+                    (sub, limits, schedule)
+                })
+                .collect::<Vec<_>>()
+        })
+        .await;
+
+        for (sub, limits, schedule) in subscriptions {
+            let mode = match DeliveryMode::from(sub.delivery_mode.as_str(), sub.digest_size) {
+                Some(mode) => mode,
+                None => {
+                    warn!(
+                        "Unknown delivery mode \"{}\" for `{}`",
+                        sub.delivery_mode, sub.subreddit
+                    );
+                    continue;
+                }
+            };
+            if limits.is_quiet(now) {
+                continue;
+            }
+            if mode == Realtime {
+                self.release_held(&sub, &limits, &schedule, now).await;
+                continue;
+            }
+
+            if !mode.is_due(sub.last_digest_at, now, &schedule) {
+                continue;
+            }
+
+            let (pool, queued) = (self.db.clone(), sub.clone());
+            let posts = db::blocking(move || {
+                DigestQueue::from(&pool).queued(
+                    &queued,
+                    mode.by_score(),
+                    mode.digest_size().map(i64::from),
+                )
+            })
+            .await;
+            if !posts.is_empty() {
+                let header = format!(
+                    "<b>{} digest</b> for {}/{}",
+                    mode,
+                    Subreddit::from(sub.subreddit.as_str()),
+                    sub.category
+                );
+                if let Err(e) = self
+                    .outbox
+                    .send_digest(ChatId(sub.user_id), header, &posts)
+                    .await
+                {
+                    error!("couldn't send digest to chat {}: {}", sub.user_id, e);
+                    continue;
+                }
+                info!(
+                    "Sent {} post(s) from `{}` as a digest to ChatID: '{}'",
+                    posts.len(),
+                    sub.subreddit,
+                    sub.user_id
+                );
+            }
+            self.digested(&sub, now).await;
+        }
+    }
+
+    /// Empties the subscription's queue once its posts went out.
+    async fn digested(&self, sub: &SubscribedListing, now: DateTime<Utc>) {
+        let (pool, sub) = (self.db.clone(), sub.clone());
+        db::blocking(move || {
+            let mut queue = DigestQueue::from(&pool);
+            queue.clear(&sub);
+            queue.mark_digested(&sub, now);
+        })
+        .await;
+    }
+
+    async fn release_held(
+        &mut self,
+        sub: &SubscribedListing,
+        limits: &ChatLimits,
+        schedule: &DigestSchedule,
+        now: DateTime<Utc>,
+    ) {
+        let chat = ClientID::from(sub.user_id);
+        match limits.overflow {
+            OverflowPolicy::Digest => {
+                if !Daily.is_due(sub.last_digest_at, now, schedule) {
+                    return;
+                }
+                let (pool, queued) = (self.db.clone(), sub.clone());
+                let posts =
+                    db::blocking(move || DigestQueue::from(&pool).queued(&queued, false, None))
+                        .await;
+                if posts.is_empty() {
+                    let (pool, sub) = (self.db.clone(), sub.clone());
+                    db::blocking(move || DigestQueue::from(&pool).mark_digested(&sub, now)).await;
+                    return;
+                }
+                let header = format!(
+                    "<b>Held back</b> from {}/{}",
+                    Subreddit::from(sub.subreddit.as_str()),
+                    sub.category
+                );
+                if let Err(e) = self
+                    .outbox
+                    .send_digest(ChatId(sub.user_id), header, &posts)
+                    .await
+                {
+                    error!("couldn't send held posts to chat {}: {}", sub.user_id, e);
+                    return;
+                }
+                self.digested(sub, now).await;
+            }
+            OverflowPolicy::Queue | OverflowPolicy::DropLowest => {
+                let by_score = limits.overflow == OverflowPolicy::DropLowest;
+                if by_score {
+                    if let Some(budget) = limits.hourly_budget {
+                        let (pool, sub) = (self.db.clone(), sub.clone());
+                        db::blocking(move || DigestQueue::from(&pool).trim(&sub, budget as usize))
+                            .await;
+                    }
+                }
+                let remaining = self.throttle.lock().await.remaining(chat, limits, now);
+                if remaining == Some(0) {
+                    return;
+                }
+
+                let (pool, queued) = (self.db.clone(), sub.clone());
+                let posts = db::blocking(move || {
+                    DigestQueue::from(&pool).queued(&queued, by_score, remaining.map(i64::from))
+                })
+                .await;
+                let mut released = Vec::new();
+                {
+                    let mut throttle = self.throttle.lock().await;
+                    for post in posts {
+                        if !throttle.try_acquire(chat, limits, now) {
+                            break;
+                        }
+                        released.push(post);
+                    }
+                }
+                if released.is_empty() {
+                    return;
+                }
+
+                let pool = self.db.clone();
+                let released = db::blocking(move || {
+                    let (mut queue, mut deliveries) =
+                        (DigestQueue::from(&pool), DeliveryQueue::from(&pool));
+                    for post in &released {
+                        deliveries.push(chat, post, false);
+                        queue.remove(chat, post);
+                    }
+                    released
+                })
+                .await;
+                for post in released {
+                    info!(
+                        "Released held PostID: '{}' to ChatID: '{}'",
+                        post.id(),
+                        sub.user_id
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_digest_due() {
+    use chrono::TimeZone;
+
+    let schedule = DigestSchedule::from("9", Some("Africa/Nairobi")).unwrap();
+    // 06:30 UTC is 09:30 in Nairobi.
+    let now = Utc.with_ymd_and_hms(2023, 5, 20, 6, 30, 0).unwrap();
+    let yesterday = Utc.with_ymd_and_hms(2023, 5, 19, 6, 30, 0).unwrap();
+    let earlier = Utc.with_ymd_and_hms(2023, 5, 20, 6, 0, 0).unwrap();
+
+    assert!(Daily.is_due(None, now, &schedule));
+    assert!(Top(5).is_due(Some(yesterday), now, &schedule));
+    assert!(!Daily.is_due(Some(earlier), now, &schedule));
+    assert!(!Daily.is_due(
+        Some(yesterday),
+        earlier - chrono::Duration::hours(1),
+        &schedule
+    ));
+    assert!(!Hourly.is_due(Some(earlier), now, &schedule));
+    assert!(Hourly.is_due(Some(yesterday), now, &schedule));
+    assert!(!Realtime.is_due(None, now, &schedule));
+    assert!(!Weekly.is_due(Some(yesterday), now, &schedule));
+    assert!(Weekly.is_due(Some(yesterday - chrono::Duration::days(6)), now, &schedule));
+}
+
+#[test]
+fn test_chat_limits() {
+    use chrono::TimeZone;
+
+    let limits = ChatLimits {
+        timezone: "Europe/Berlin".parse().unwrap(),
+        quiet_hours: Some((22, 7)),
+        hourly_budget: Some(2),
+        overflow: OverflowPolicy::Queue,
+    };
+    // 21:30 UTC is 23:30 in Berlin during summer time.
+    let night = Utc.with_ymd_and_hms(2023, 5, 20, 21, 30, 0).unwrap();
+    let noon = Utc.with_ymd_and_hms(2023, 5, 20, 10, 0, 0).unwrap();
+    assert!(limits.is_quiet(night));
+    assert!(!limits.is_quiet(noon));
+
+    let chat = ClientID::from(1);
+    let mut throttle = DeliveryThrottle::default();
+    assert!(!throttle.try_acquire(chat, &limits, night));
+    assert!(throttle.try_acquire(chat, &limits, noon));
+    assert!(throttle.try_acquire(chat, &limits, noon));
+    assert!(!throttle.try_acquire(chat, &limits, noon));
+    assert_eq!(
+        throttle.remaining(chat, &limits, noon + chrono::Duration::hours(1)),
+        Some(2)
+    );
+}
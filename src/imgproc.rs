@@ -1,5 +1,15 @@
+use std::io::Cursor;
+
 use image::imageops::{resize, FilterType};
-use image::RgbImage;
+use image::{DynamicImage, GenericImageView, ImageOutputFormat, RgbImage};
+
+// Telegram rejects photos over 10MB, whose width and height add up to more
+// than 10000 pixels, or whose aspect ratio is beyond 20.
+pub const PHOTO_SIZE_MAX: usize = 10 * 1024 * 1024;
+const PHOTO_DIMENSIONS_MAX: u32 = 10000;
+const PHOTO_RATIO_MAX: u32 = 20;
+
+const PHOTO_JPEG_QUALITY: u8 = 90;
 
 fn is_same(img1: &RgbImage, img2: &RgbImage) -> bool {
     let img1 = resize(img1, 4, 4, FilterType::CatmullRom);
@@ -29,3 +39,76 @@ fn is_same(img1: &RgbImage, img2: &RgbImage) -> bool {
     // arbitrary similarity score threshold
     score < 500
 }
+
+/// Why an image couldn't be made into a photo Telegram accepts.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PhotoError {
+    /// Not an image at all, e.g. a web page served in its place.
+    Undecodable,
+    /// An image that would have to be cropped or shrunk too far to fit.
+    WontFit,
+}
+
+/// Re-encodes an image as a JPEG within Telegram's photo limits, scaling it
+/// down as needed.
+pub fn fit_photo(bytes: &[u8]) -> Result<Vec<u8>, PhotoError> {
+    let img = image::load_from_memory(bytes).map_err(|_| PhotoError::Undecodable)?;
+    let (width, height) = img.dimensions();
+    if width.max(height) > width.min(height).max(1) * PHOTO_RATIO_MAX {
+        return Err(PhotoError::WontFit);
+    }
+
+    let mut img = if width + height > PHOTO_DIMENSIONS_MAX {
+        let scale = PHOTO_DIMENSIONS_MAX as f64 / (width + height) as f64;
+        scaled(&img, scale)
+    } else {
+        img
+    };
+
+    for _ in 0..4 {
+        let mut buf = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(img.to_rgb8())
+            .write_to(&mut buf, ImageOutputFormat::Jpeg(PHOTO_JPEG_QUALITY))
+            .map_err(|_| PhotoError::WontFit)?;
+        if buf.get_ref().len() <= PHOTO_SIZE_MAX {
+            return Ok(buf.into_inner());
+        }
+        img = scaled(&img, 0.75);
+    }
+    Err(PhotoError::WontFit)
+}
+
+fn scaled(img: &DynamicImage, scale: f64) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    img.resize(
+        ((width as f64 * scale) as u32).max(1),
+        ((height as f64 * scale) as u32).max(1),
+        FilterType::CatmullRom,
+    )
+}
+
+#[test]
+fn test_fit_photo() {
+    let encode = |img: RgbImage| {
+        let mut buf = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(img)
+            .write_to(&mut buf, ImageOutputFormat::Png)
+            .unwrap();
+        buf.into_inner()
+    };
+
+    let photo = fit_photo(&encode(RgbImage::new(300, 200))).unwrap();
+    assert_eq!(
+        image::load_from_memory(&photo).unwrap().dimensions(),
+        (300, 200)
+    );
+
+    assert_eq!(
+        fit_photo(&encode(RgbImage::new(2100, 100))),
+        Err(PhotoError::WontFit)
+    );
+    assert_eq!(
+        fit_photo(b"<html>not an image</html>"),
+        Err(PhotoError::Undecodable)
+    );
+}
@@ -1,5 +1,6 @@
 mod deviant_art;
 pub mod imgur;
+pub mod ratelimit;
 pub mod reddit;
 pub mod source;
 pub mod twitter;
@@ -0,0 +1,232 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use tokio::sync::Mutex;
+use tokio::time::{sleep_until, Instant};
+
+// Reddit allows 100 requests per minute to OAuth clients, averaged over a
+// 10 minute window. Used until the first response tells us otherwise.
+const WINDOW_REQUESTS: f64 = 600.0;
+const WINDOW_SECS: u64 = 600;
+
+// Below this share of the window's budget, usage is reported as a warning.
+const LOW_BUDGET: f64 = 0.1;
+
+/// Request budget of the current rate-limit window, as last reported by
+/// Reddit's `X-Ratelimit-*` headers.
+#[derive(Debug)]
+struct Budget {
+    remaining: f64,
+    used: u64,
+    reset_at: Instant,
+    last_slot: Option<Instant>,
+}
+
+impl Budget {
+    fn new(now: Instant) -> Self {
+        Self {
+            remaining: WINDOW_REQUESTS,
+            used: 0,
+            reset_at: now + Duration::from_secs(WINDOW_SECS),
+            last_slot: None,
+        }
+    }
+
+    /// When the next request may be sent, spreading what's left of the
+    /// budget evenly until the window resets.
+    fn slot(&self, now: Instant) -> Instant {
+        if self.remaining < 1.0 {
+            return self.reset_at;
+        }
+        let last = match self.last_slot {
+            Some(last) => last,
+            None => return now,
+        };
+        now.max(last + self.spacing(now))
+    }
+
+    /// Time between two requests that would use up the budget exactly as the
+    /// window resets.
+    fn spacing(&self, now: Instant) -> Duration {
+        if self.remaining < 1.0 {
+            return self.reset_at.saturating_duration_since(now);
+        }
+        Duration::from_secs_f64(
+            self.reset_at.saturating_duration_since(now).as_secs_f64() / self.remaining,
+        )
+    }
+
+    fn spend(&mut self, at: Instant) {
+        self.remaining -= 1.0;
+        self.used += 1;
+        self.last_slot = Some(at);
+    }
+
+    fn update(&mut self, headers: &HeaderMap, now: Instant) {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse::<f64>().ok())
+        };
+        if let Some(remaining) = header("x-ratelimit-remaining") {
+            self.remaining = remaining;
+        }
+        if let Some(used) = header("x-ratelimit-used") {
+            self.used = used as u64;
+        }
+        if let Some(reset) = header("x-ratelimit-reset") {
+            self.reset_at = now + Duration::from_secs_f64(reset);
+        }
+    }
+}
+
+/// Paces requests to the Reddit API so that every listing polled through
+/// clones of the same `Api` stays within one shared budget. Waiting requests
+/// are served in the order they arrived.
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    budget: Arc<Mutex<Budget>>,
+    // Last known `Budget::spacing`, in milliseconds, readable without
+    // queueing behind the requests waiting on `budget`.
+    spacing: Arc<AtomicU64>,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        let budget = Budget::new(Instant::now());
+        let spacing = budget.spacing(Instant::now()).as_millis() as u64;
+        Self {
+            budget: Arc::new(Mutex::new(budget)),
+            spacing: Arc::new(AtomicU64::new(spacing)),
+        }
+    }
+}
+
+impl RateLimit {
+    /// Waits for a share of the budget to send one request.
+    pub async fn acquire(&self) {
+        loop {
+            // Slots are reserved in turn, so requests still leave in the
+            // order they asked, but the lock isn't held while waiting for one
+            // and `update` can record fresh headers meanwhile.
+            let (slot, reserved) = {
+                let mut budget = self.budget.lock().await;
+                let now = Instant::now();
+                if now >= budget.reset_at {
+                    info!(
+                        "Reddit API rate-limit window reset, {} request(s) used in the last one",
+                        budget.used
+                    );
+                    *budget = Budget::new(now);
+                }
+
+                let slot = budget.slot(now);
+                if budget.remaining < 1.0 {
+                    warn!(
+                        "Reddit API budget exhausted, waiting {}s for the window to reset",
+                        slot.saturating_duration_since(now).as_secs()
+                    );
+                    (slot, false)
+                } else {
+                    budget.spend(slot);
+                    self.publish(&budget, slot);
+                    (slot, true)
+                }
+            };
+            sleep_until(slot).await;
+            if reserved {
+                return;
+            }
+        }
+    }
+
+    /// How often requests can currently be sent without running out of
+    /// budget before the window resets.
+    pub fn spacing(&self) -> Duration {
+        Duration::from_millis(self.spacing.load(Ordering::Relaxed))
+    }
+
+    fn publish(&self, budget: &Budget, now: Instant) {
+        let spacing = budget.spacing(now).as_millis() as u64;
+        self.spacing.store(spacing, Ordering::Relaxed);
+    }
+
+    /// Syncs the budget with the rate-limit headers of a response.
+    pub async fn update(&self, status: StatusCode, headers: &HeaderMap) {
+        let mut budget = self.budget.lock().await;
+        let now = Instant::now();
+        budget.update(headers, now);
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            warn!("Reddit API rate limit hit despite pacing requests");
+            budget.remaining = 0.0;
+        }
+
+        self.publish(&budget, now);
+
+        let resets_in = budget.reset_at.saturating_duration_since(now).as_secs();
+        if budget.remaining < WINDOW_REQUESTS * LOW_BUDGET {
+            warn!(
+                "Reddit API budget running low: {} used, {} remaining, resets in {}s",
+                budget.used, budget.remaining, resets_in
+            );
+        } else {
+            debug!(
+                "Reddit API budget: {} used, {} remaining, resets in {}s",
+                budget.used, budget.remaining, resets_in
+            );
+        }
+    }
+}
+
+#[test]
+fn test_rate_limit_budget() {
+    use reqwest::header::HeaderValue;
+
+    let now = Instant::now();
+    let mut budget = Budget::new(now);
+    assert_eq!(budget.slot(now), now);
+
+    let mut headers = HeaderMap::new();
+    headers.insert("x-ratelimit-remaining", HeaderValue::from_static("10.0"));
+    headers.insert("x-ratelimit-used", HeaderValue::from_static("590"));
+    headers.insert("x-ratelimit-reset", HeaderValue::from_static("100"));
+    budget.update(&headers, now);
+    assert_eq!(budget.used, 590);
+    assert_eq!(budget.reset_at, now + Duration::from_secs(100));
+
+    // What's left is spread over the rest of the window.
+    budget.spend(now);
+    assert_eq!(budget.spacing(now), Duration::from_secs_f64(100.0 / 9.0));
+    assert_eq!(budget.slot(now), now + Duration::from_secs_f64(100.0 / 9.0));
+
+    budget.remaining = 0.0;
+    assert_eq!(budget.slot(now), budget.reset_at);
+}
+
+#[tokio::test]
+async fn test_update_while_waiting() {
+    use reqwest::header::HeaderValue;
+    use tokio::time::timeout;
+
+    let limit = RateLimit::default();
+    let mut headers = HeaderMap::new();
+    headers.insert("x-ratelimit-remaining", HeaderValue::from_static("2"));
+    headers.insert("x-ratelimit-reset", HeaderValue::from_static("100"));
+    limit.update(StatusCode::OK, &headers).await;
+
+    // The second request waits about 50s for its slot, without keeping the
+    // headers of other responses from being recorded.
+    limit.acquire().await;
+    let waiting = limit.clone();
+    let request = tokio::spawn(async move { waiting.acquire().await });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!request.is_finished());
+    let update = limit.update(StatusCode::OK, &headers);
+    assert!(timeout(Duration::from_secs(1), update).await.is_ok());
+    request.abort();
+}
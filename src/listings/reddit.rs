@@ -1,227 +1,593 @@
-use std::collections::{HashMap, VecDeque};
-use std::env;
+use std::collections::VecDeque;
+use std::sync::Arc;
 use std::time::Duration;
 
 use async_trait::async_trait;
-use dotenvy::dotenv;
-use log::info;
-use reqwest::Response;
+use chrono::{TimeZone, Utc};
+use log::{info, warn};
+use reqwest::header::HeaderMap;
+use reqwest::{Response, StatusCode};
+use serde::Deserialize;
 use serde_json::Value;
+use tokio::sync::Mutex;
 use tokio::time::Instant;
 
 use crate::content::Post;
-use crate::listings::reddit::Listing::{Hot, New, Random, Rising, Sort};
-use crate::listings::source::ListingSource;
+use crate::listings::ratelimit::RateLimit;
+use crate::listings::reddit::Listing::{Controversial, Hot, New, Random, Rising, Top};
+use crate::listings::source::{ListingSource, SourceError};
 
 const REDDIT_USER_AGENT: &str = "windows:com.example.artbutler:v0.3.1 (by /u/mcctor)";
 
+// Tokens are renewed this long before they expire.
+const TOKEN_REFRESH_MARGIN: u64 = 60;
+
+// Hours between random posts unless a subscription says otherwise.
+const RANDOM_INTERVAL_DEFAULT: u32 = 24;
+
+// How long to back off when a 429 doesn't say when to retry.
+const RATE_LIMIT_BACKOFF: u64 = 60;
+
 enum AuthTokenAction {
     New,
     Refresh,
 }
 
+/// How the bot obtains Reddit API tokens, picked by `reddit.grant`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Grant {
+    /// Logs in as `reddit.username`, only works for script apps.
+    Password { username: String, password: String },
+    /// Application-only access for confidential (web or script) apps.
+    #[default]
+    ClientCredentials,
+    /// Application-only access for installed apps, which have no secret.
+    InstalledClient { device_id: String },
+    /// Acts on behalf of the user who authorized the app, exchanging the
+    /// code from the authorization redirect for a refresh token once.
+    AuthorizationCode {
+        code: Option<String>,
+        redirect_uri: String,
+        refresh_token: Option<String>,
+    },
+}
+
+impl Grant {
+    fn params(
+        &self,
+        auth_option: AuthTokenAction,
+        token: Option<&BearerToken>,
+    ) -> Vec<(&'static str, String)> {
+        let refresh_token = match auth_option {
+            AuthTokenAction::Refresh => token.and_then(|t| t.refresh_token.clone()),
+            AuthTokenAction::New => None,
+        };
+        if let Some(refresh_token) = refresh_token {
+            return vec![
+                ("grant_type", "refresh_token".to_string()),
+                ("refresh_token", refresh_token),
+            ];
+        }
+
+        match self {
+            Grant::Password { username, password } => vec![
+                ("grant_type", "password".to_string()),
+                ("username", username.to_string()),
+                ("password", password.to_string()),
+            ],
+            Grant::ClientCredentials => vec![("grant_type", "client_credentials".to_string())],
+            Grant::InstalledClient { device_id } => vec![
+                (
+                    "grant_type",
+                    "https://oauth.reddit.com/grants/installed_client".to_string(),
+                ),
+                ("device_id", device_id.to_string()),
+            ],
+            Grant::AuthorizationCode {
+                refresh_token: Some(refresh_token),
+                ..
+            } => vec![
+                ("grant_type", "refresh_token".to_string()),
+                ("refresh_token", refresh_token.to_string()),
+            ],
+            Grant::AuthorizationCode {
+                code, redirect_uri, ..
+            } => vec![
+                ("grant_type", "authorization_code".to_string()),
+                ("code", code.clone().unwrap_or_default()),
+                ("redirect_uri", redirect_uri.to_string()),
+            ],
+        }
+    }
+}
+
+/// The Reddit app the bot authenticates as, and how.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Credentials {
+    pub client_id: String,
+    /// Empty for installed apps, which have no secret.
+    pub secret: String,
+    pub grant: Grant,
+}
+
 #[derive(Debug, Clone)]
 pub struct BearerToken {
     token: String,
+    refresh_token: Option<String>,
     expires: Instant,
 }
 
+impl BearerToken {
+    fn is_fresh(&self) -> bool {
+        Instant::now() + Duration::from_secs(TOKEN_REFRESH_MARGIN) < self.expires
+    }
+}
+
+/// The bearer token shared by every clone of an `Api`. Callers that find it
+/// missing or about to expire while another renews it wait for that renewal
+/// instead of logging in again themselves.
+#[derive(Debug, Clone, Default)]
+struct TokenManager {
+    token: Arc<Mutex<Option<BearerToken>>>,
+    credentials: Arc<Credentials>,
+}
+
+impl TokenManager {
+    async fn bearer(&self, cli: &reqwest::Client) -> Result<String, SourceError> {
+        let mut token = self.token.lock().await;
+        let renewed = match token.as_ref() {
+            Some(t) if t.is_fresh() => return Ok(t.token.to_string()),
+            Some(t) if t.refresh_token.is_some() => {
+                let renewed = self
+                    .authenticate(cli, AuthTokenAction::Refresh, Some(t))
+                    .await;
+                match renewed {
+                    Ok(renewed) => {
+                        info!("Reddit API bearer token refreshed");
+                        renewed
+                    }
+                    Err(e) => {
+                        warn!("couldn't refresh Reddit API token, logging in again: {}", e);
+                        self.authenticate(cli, AuthTokenAction::New, None).await?
+                    }
+                }
+            }
+            _ => self.authenticate(cli, AuthTokenAction::New, None).await?,
+        };
+        let bearer = renewed.token.to_string();
+        *token = Some(renewed);
+        Ok(bearer)
+    }
+
+    /// Drops a token Reddit refused, so the next request authenticates again.
+    async fn invalidate(&self) {
+        self.token.lock().await.take();
+    }
+
+    async fn authenticate(
+        &self,
+        cli: &reqwest::Client,
+        auth_option: AuthTokenAction,
+        token: Option<&BearerToken>,
+    ) -> Result<BearerToken, SourceError> {
+        let Credentials {
+            client_id,
+            secret,
+            grant,
+        } = self.credentials.as_ref();
+        let res = cli
+            .post("https://www.reddit.com/api/v1/access_token")
+            .basic_auth(client_id, Some(secret))
+            .header("User-Agent", REDDIT_USER_AGENT)
+            .form(&grant.params(auth_option, token))
+            .send()
+            .await?;
+        if res.status().is_client_error() {
+            return Err(SourceError::Auth(format!(
+                "token request rejected with {}",
+                res.status()
+            )));
+        }
+
+        let value = res.error_for_status()?.json::<Value>().await?;
+        if let Some(e) = value["error"].as_str() {
+            return Err(SourceError::Auth(e.to_string()));
+        }
+        let refresh_token = value["refresh_token"].as_str().map(String::from);
+        // The refresh token is a long-lived secret, so it's kept in memory
+        // rather than logged for the operator to copy.
+        if let (
+            Grant::AuthorizationCode {
+                code: Some(_),
+                refresh_token: None,
+                ..
+            },
+            Some(_),
+        ) = (grant, refresh_token.as_ref())
+        {
+            warn!(
+                "Authorization code exchanged, its refresh token only lasts until the bot stops. \
+                 Codes work once: to keep access across restarts, exchange a new code with \
+                 Reddit yourself and set reddit.refresh_token (REDDIT_REFRESH_TOKEN) to the \
+                 refresh_token it returns"
+            );
+        }
+        let access_token = value["access_token"]
+            .as_str()
+            .ok_or_else(|| SourceError::Parse("token response without access_token".into()))?;
+        let expires_in = value["expires_in"]
+            .as_u64()
+            .ok_or_else(|| SourceError::Parse("token response without expires_in".into()))?;
+        let token = BearerToken {
+            token: access_token.to_string(),
+            refresh_token: refresh_token.or_else(|| token.and_then(|t| t.refresh_token.clone())),
+            expires: Instant::now() + Duration::from_secs(expires_in),
+        };
+
+        info!("Reddit API is authenticated");
+        Ok(token)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Api {
     cli: reqwest::Client,
-    has_token: Option<BearerToken>,
+    token: TokenManager,
+    limit: RateLimit,
 }
 
 impl Default for Api {
     fn default() -> Self {
         Self {
             cli: reqwest::Client::new(),
-            has_token: None,
+            token: TokenManager::default(),
+            limit: RateLimit::default(),
         }
     }
 }
 
 #[async_trait]
 impl ListingSource for Api {
-    async fn retrieve_posts(&mut self, listing: &mut Listing) -> reqwest::Result<VecDeque<Post>> {
+    fn name(&self) -> &'static str {
+        "reddit"
+    }
+
+    fn request_spacing(&self) -> Duration {
+        self.limit.spacing()
+    }
+
+    async fn retrieve_posts(
+        &mut self,
+        listing: &mut Listing,
+    ) -> Result<VecDeque<Post>, SourceError> {
         let resp = self.request(listing).await?;
-        let posts = self.serialize(resp, listing.result_limit()).await?;
-        if !posts.is_empty() {
-            listing.update_paginator_cache(&posts);
-        }
+        let (posts, page) = self.serialize(resp, listing.result_limit()).await?;
+        listing.advance(&page);
 
         Ok(posts)
     }
 }
 
 impl Api {
-    pub fn from(cli: &reqwest::Client) -> Self {
+    pub fn from(cli: &reqwest::Client, credentials: Credentials) -> Self {
         Api {
             cli: cli.clone(),
-            has_token: None,
+            token: TokenManager {
+                token: Default::default(),
+                credentials: Arc::new(credentials),
+            },
+            limit: RateLimit::default(),
         }
     }
 
-    async fn authenticate(
-        &mut self,
-        auth_option: AuthTokenAction,
-    ) -> reqwest::Result<&BearerToken> {
-        dotenv().ok();
-
-        let client_id = env::var("CLIENT_ID").expect("CLIENT_ID not provided");
-        let secret = env::var("SECRET").expect("SECRET not provided");
-        let username = env::var("USER_NAME").expect("USERNAME not provided");
-        let pass = env::var("PASSWORD").expect("PASSWORD not provided");
-
-        let args = Api::auth_url_args(username, pass, self.has_token.as_ref(), auth_option);
-        let url = format!("https://www.reddit.com/api/v1/access_token{}", args);
-        let res = self
-            .cli
-            .post(url)
-            .basic_auth(client_id, Some(secret))
-            .header("User-Agent", REDDIT_USER_AGENT)
-            .send()
-            .await?;
-
-        let value = res.json::<Value>().await?;
-        let token = Some(BearerToken {
-            token: value["access_token"].as_str().unwrap().to_string(),
-            expires: Instant::now() + Duration::from_secs(value["expires_in"].as_u64().unwrap()),
-        });
-
-        info!("Reddit API is authenticated");
-        self.has_token = token;
-        Ok(self.has_token.as_ref().unwrap())
-    }
-
-    fn auth_url_args(
-        username: String,
-        pass: String,
-        token: Option<&BearerToken>,
-        auth_option: AuthTokenAction,
-    ) -> String {
-        let mut args = String::new();
-        match auth_option {
-            AuthTokenAction::New => {
-                let params = format!(
-                    "?grant_type=password&username={}&password={}",
-                    username, pass
-                );
-                args.push_str(params.as_str());
-            }
-            AuthTokenAction::Refresh => {
-                let params = format!(
-                    "?grant_type=refresh_token&refresh_token={}",
-                    token.as_ref().unwrap().token
-                );
-                args.push_str(params.as_str());
-            }
-        };
-        args
-    }
-
-    pub async fn authenticate_or_refresh(&mut self) -> reqwest::Result<&BearerToken> {
-        if let Some(t) = self.has_token.as_ref() {
-            if Instant::now() > (t.expires - Duration::from_secs(60)) {
-                info!("Reddit API bearer token refreshed");
-                self.authenticate(AuthTokenAction::Refresh).await?;
-            }
-            Ok(self.has_token.as_ref().unwrap())
-        } else {
-            self.authenticate(AuthTokenAction::New).await
-        }
+    /// A valid bearer token, logging in or refreshing first when needed.
+    pub async fn authenticate_or_refresh(&self) -> Result<String, SourceError> {
+        self.token.bearer(&self.cli).await
     }
 
-    async fn request(&mut self, listing: &Listing) -> reqwest::Result<Response> {
+    async fn request(&mut self, listing: &Listing) -> Result<Response, SourceError> {
         let req_builder = self.cli.get(listing.endpoint());
         let bearer = self.authenticate_or_refresh().await?;
+        self.limit.acquire().await;
         let res = req_builder
-            .bearer_auth(bearer.token.to_string())
+            .bearer_auth(bearer)
             .header("User-Agent", REDDIT_USER_AGENT)
             .send()
             .await?;
+        self.limit.update(res.status(), res.headers()).await;
+
+        match res.status() {
+            StatusCode::UNAUTHORIZED => {
+                self.token.invalidate().await;
+                Err(SourceError::Auth("bearer token rejected".into()))
+            }
+            StatusCode::FORBIDDEN => Err(SourceError::Forbidden),
+            StatusCode::NOT_FOUND => Err(SourceError::NotFound),
+            StatusCode::TOO_MANY_REQUESTS => Err(SourceError::RateLimited {
+                retry_after: Api::retry_after(res.headers()),
+            }),
+            // Reddit redirects requests for subreddits that never existed
+            // to its search page.
+            _ if res.url().path().starts_with("/subreddits/search") => Err(SourceError::NotFound),
+            _ => Ok(res.error_for_status()?),
+        }
+    }
 
-        Ok(res)
+    fn retry_after(headers: &HeaderMap) -> Duration {
+        ["retry-after", "x-ratelimit-reset"]
+            .iter()
+            .filter_map(|name| {
+                headers
+                    .get(*name)?
+                    .to_str()
+                    .ok()?
+                    .trim()
+                    .parse::<f64>()
+                    .ok()
+            })
+            .next()
+            .map(Duration::from_secs_f64)
+            .unwrap_or(Duration::from_secs(RATE_LIMIT_BACKOFF))
     }
 
     async fn serialize(
         &self,
         resp: Response,
         result_count: u64,
-    ) -> reqwest::Result<VecDeque<Post>> {
-        let raw_json = resp.json::<Value>().await?;
-        let mut posts = VecDeque::new();
-        for i in 0..result_count {
-            let post_raw = &raw_json["data"]["children"][i as usize]["data"];
-            let post = self.parse_post(&post_raw);
-            if *post.id() != "ul".to_string() {
-                posts.push_front(post);
+    ) -> Result<(VecDeque<Post>, PageTokens), SourceError> {
+        let page = match resp.json::<ListingBody>().await? {
+            ListingBody::Page(page) => page,
+            // Random posts come with their comments as a second listing.
+            ListingBody::Post(mut pages) if !pages.is_empty() => pages.remove(0),
+            ListingBody::Post(_) => {
+                return Err(SourceError::Parse("empty listing array".into()));
             }
-        }
-
-        Ok(posts)
+        };
+        Ok(page.into_page(result_count as usize))
     }
+}
 
-    fn parse_post(&self, raw_json: &Value) -> Post {
-        let mut fields = HashMap::new();
-        {
-            fields.insert("id".to_string(), raw_json["id"].to_string());
-            fields.insert("url".to_string(), raw_json["url"].to_string());
-            fields.insert("author".to_string(), raw_json["author"].to_string());
-            fields.insert("title".to_string(), raw_json["title"].to_string());
-            Api::normalize(&mut fields);
-        }
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ListingBody {
+    Page(ListingResponse),
+    Post(Vec<ListingResponse>),
+}
 
-        let ups = match raw_json["ups"].to_string().parse() {
-            Ok(value) => value,
-            Err(_) => 0,
-        };
-        let downs = match raw_json["downs"].to_string().parse() {
-            Ok(value) => value,
-            Err(_) => 0,
-        };
+/// A page of a Reddit listing.
+#[derive(Debug, Deserialize)]
+struct ListingResponse {
+    data: ListingData,
+}
 
-        let post_url = {
-            let perma = raw_json["permalink"].as_str().unwrap_or("");
-            if perma.is_empty() {
-                "".to_string()
-            } else {
-                format!("https://www.reddit.com{}", perma)
-            }
-        };
+#[derive(Debug, Deserialize)]
+struct ListingData {
+    children: Vec<Thing>,
+    #[serde(default)]
+    after: Option<String>,
+    #[serde(default)]
+    before: Option<String>,
+}
 
-        Post::new(
-            fields.remove("id").unwrap(),
-            fields.remove("url").unwrap(),
-            fields.remove("author").unwrap(),
-            fields.remove("title").unwrap(),
-            (ups, downs),
-        )
+/// A listing entry, of which only links (`t3`) are posts.
+#[derive(Debug, Deserialize)]
+struct Thing {
+    kind: String,
+    data: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct Link {
+    id: String,
+    /// The fullname, e.g. `t3_{id}`, Reddit paginates with.
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    title: String,
+    #[serde(default = "Link::deleted_author")]
+    author: String,
+    #[serde(default)]
+    url: Option<String>,
+    #[serde(default)]
+    ups: i32,
+    #[serde(default)]
+    downs: i32,
+    /// Seconds since the epoch, which Reddit sends as a float.
+    #[serde(default)]
+    created_utc: Option<f64>,
+}
+
+impl Link {
+    fn deleted_author() -> String {
+        "[deleted]".to_string()
     }
+}
 
-    fn normalize(elems: &mut HashMap<String, String>) {
-        for (_, value) in elems.iter_mut() {
-            value.remove(0);
-            value.remove(value.len() - 1);
+impl ListingResponse {
+    /// The first `limit` links of the page, oldest first, and the tokens to
+    /// request the pages around it with.
+    fn into_page(self, limit: usize) -> (VecDeque<Post>, PageTokens) {
+        let mut page = PageTokens {
+            first: self.data.before,
+            after: self.data.after,
+            received: 0,
+        };
+        let mut posts = VecDeque::new();
+        let links = self
+            .data
+            .children
+            .into_iter()
+            .filter(|thing| thing.kind == "t3")
+            .filter_map(|thing| match serde_json::from_value::<Link>(thing.data) {
+                Ok(link) => Some(link),
+                Err(e) => {
+                    warn!("Skipping malformed Reddit link: {}", e);
+                    None
+                }
+            })
+            .take(limit);
+        for link in links {
+            if page.received == 0 {
+                page.first = link.name.clone().or(page.first);
+            }
+            page.received += 1;
+            let posted_at = link
+                .created_utc
+                .and_then(|secs| Utc.timestamp_opt(secs as i64, 0).single());
+            let post = Post::new(
+                link.id,
+                link.url.unwrap_or_default(),
+                link.author,
+                link.title,
+                (link.ups, link.downs),
+            );
+            posts.push_front(match posted_at {
+                Some(at) => post.posted(at),
+                None => post,
+            });
         }
+        (posts, page)
     }
 }
 
+/// Where a page of a listing sits within it.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PageTokens {
+    /// Fullname of the page's first, i.e. newest or highest ranked, post.
+    pub first: Option<String>,
+    /// Token for the page that follows, `None` at the end of the listing.
+    pub after: Option<String>,
+    pub received: u64,
+}
+
+/// What a listing is read from, persisted by its `name()`: a subreddit like
+/// `Art`, subreddits combined as `Art+Painting`, a user's submissions as
+/// `u/name`, a custom multireddit as `m/owner/name`, or a search as
+/// `search:query`, optionally within a subreddit as `Art/search:query`.
 #[derive(Debug, Clone, Eq, Hash, PartialEq)]
 pub struct Subreddit(String);
 
+/// The kinds of `Subreddit` a listing can be read from.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Target<'a> {
+    Subreddits(&'a str),
+    User(&'a str),
+    Multi {
+        owner: &'a str,
+        name: &'a str,
+    },
+    Search {
+        within: Option<&'a str>,
+        query: &'a str,
+    },
+}
+
 impl Subreddit {
     pub fn name(&self) -> String {
         self.0.to_string()
     }
+
+    pub fn target(&self) -> Target<'_> {
+        let name = self.0.as_str();
+        if let Some(user) = name.strip_prefix("u/") {
+            return Target::User(user);
+        }
+        if let Some((owner, multi)) = name.strip_prefix("m/").and_then(|m| m.split_once('/')) {
+            return Target::Multi { owner, name: multi };
+        }
+        if let Some(query) = name.strip_prefix("search:") {
+            return Target::Search {
+                within: None,
+                query,
+            };
+        }
+        if let Some((within, query)) = name.split_once("/search:") {
+            return Target::Search {
+                within: Some(within),
+                query,
+            };
+        }
+        Target::Subreddits(name)
+    }
+
+    /// Whether listings of `category` can be read from this target.
+    fn supports(&self, category: &str) -> bool {
+        match self.target() {
+            Target::Subreddits(_) => true,
+            Target::Multi { .. } => category != "random",
+            Target::User(_) => matches!(category, "new" | "hot" | "top" | "controversial"),
+            Target::Search { .. } => matches!(category, "new" | "hot" | "top"),
+        }
+    }
+
+    /// Path of the `category` listing of this target, `args` being its query
+    /// string.
+    fn path(&self, category: &str, args: String) -> String {
+        match self.target() {
+            Target::Subreddits(names) => format!("/r/{}/{}{}", names, category, args),
+            Target::User(user) => format!("/user/{}/submitted{}&sort={}", user, args, category),
+            Target::Multi { owner, name } => {
+                format!("/user/{}/m/{}/{}{}", owner, name, category, args)
+            }
+            Target::Search { within, query } => {
+                let query = Subreddit::encode(&query.replace('+', " "));
+                match within {
+                    Some(sub) => format!(
+                        "/r/{}/search{}&q={}&sort={}&restrict_sr=1&type=link",
+                        sub, args, query, category
+                    ),
+                    None => format!("/search{}&q={}&sort={}&type=link", args, query, category),
+                }
+            }
+        }
+    }
+
+    fn encode(query: &str) -> String {
+        query
+            .bytes()
+            .map(|b| match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                    (b as char).to_string()
+                }
+                b => format!("%{:02X}", b),
+            })
+            .collect()
+    }
 }
 
-impl<T: ToString> From<T> for Subreddit {
-    fn from(s: T) -> Self {
-        Self(s.to_string())
+impl std::fmt::Display for Subreddit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.target() {
+            Target::Subreddits(names) => write!(f, "r/{}", names),
+            Target::User(user) => write!(f, "u/{}", user),
+            Target::Multi { owner, name } => write!(f, "m/{}/{}", owner, name),
+            Target::Search {
+                within: Some(sub),
+                query,
+            } => write!(f, "r/{} search \"{}\"", sub, query.replace('+', " ")),
+            Target::Search {
+                within: None,
+                query,
+            } => {
+                write!(f, "search \"{}\"", query.replace('+', " "))
+            }
+        }
+    }
+}
+
+impl From<&str> for Subreddit {
+    fn from(name: &str) -> Self {
+        Self(name.strip_prefix("r/").unwrap_or(name).to_string())
+    }
+}
+
+impl From<String> for Subreddit {
+    fn from(name: String) -> Self {
+        Subreddit::from(name.as_str())
     }
 }
 
+/// Window `top` and `controversial` listings rank posts over.
 #[derive(PartialEq, Debug, Copy, Clone, Eq, Hash)]
 pub enum Time {
     Hour,
@@ -232,45 +598,73 @@ pub enum Time {
     All,
 }
 
-#[derive(PartialEq, Debug, Clone, Eq, Hash)]
+impl Time {
+    pub fn from(window: &str) -> Option<Time> {
+        match window {
+            "hour" => Some(Time::Hour),
+            "day" => Some(Time::Day),
+            "week" => Some(Time::Week),
+            "month" => Some(Time::Month),
+            "year" => Some(Time::Year),
+            "all" => Some(Time::All),
+            _ => None,
+        }
+    }
+
+    pub fn tag(&self) -> &'static str {
+        match self {
+            Time::Hour => "hour",
+            Time::Day => "day",
+            Time::Week => "week",
+            Time::Month => "month",
+            Time::Year => "year",
+            Time::All => "all",
+        }
+    }
+}
+
+/// Which way a listing is walked.
+#[derive(PartialEq, Debug, Copy, Clone, Eq, Hash)]
 pub enum Seek {
-    After { cache: VecDeque<Post> },
-    Back { cache: VecDeque<Post> },
+    /// Polls for posts that appeared since the last request.
+    Newer,
+    /// Walks back through older pages, e.g. to backfill a subreddit.
+    Older,
 }
 
 #[derive(PartialEq, Debug, Clone, Eq, Hash)]
 pub struct Pagination {
-    cursor_anchor: Seek,
+    seek: Seek,
+    /// Fullname to continue from, as returned by Reddit.
+    anchor: Option<String>,
     limit: u64,
+    /// Posts seen while walking back, which Reddit wants as `count`.
     seen_count: u64,
-    show_rules: String,
+    exhausted: bool,
 }
 
 impl Pagination {
     pub fn builder() -> Self {
         Pagination {
-            cursor_anchor: Seek::After {
-                cache: Default::default(),
-            },
+            seek: Seek::Newer,
+            anchor: None,
             limit: 0,
             seen_count: 0,
-            show_rules: "".to_string(),
+            exhausted: false,
         }
     }
 
     pub fn cursor(&self) -> &Seek {
-        &self.cursor_anchor
+        &self.seek
     }
 
-    pub fn set_cursor(&mut self, p: Post) -> &mut Self {
-        match &mut self.cursor_anchor {
-            Seek::After { cache } => {
-                cache.push_back(p);
-            }
-            Seek::Back { cache } => {
-                cache.push_back(p);
-            }
-        };
+    /// Fullname the next request continues from.
+    pub fn anchor(&self) -> Option<&String> {
+        self.anchor.as_ref()
+    }
+
+    pub fn set_anchor(&mut self, anchor: String) -> &mut Self {
+        self.anchor = Some(anchor);
         self
     }
 
@@ -279,17 +673,52 @@ impl Pagination {
         self
     }
 
-    pub fn seek_forward(&mut self) {
-        self.cursor_anchor = Seek::After {
-            cache: Default::default(),
-        }
+    /// Whether walking back reached the end of the listing.
+    pub fn is_exhausted(&self) -> bool {
+        self.exhausted
     }
 
     pub fn seek_back(&mut self) {
-        self.cursor_anchor = Seek::Back {
-            cache: Default::default(),
+        self.seek = Seek::Older;
+        self.reset();
+    }
+
+    fn reset(&mut self) {
+        self.anchor = None;
+        self.seen_count = 0;
+        self.exhausted = false;
+    }
+
+    /// Moves past `page`. Only chronological listings can be polled for newer
+    /// posts with `before`, as ranked ones reorder between requests and are
+    /// re-read from the top instead.
+    fn advance(&mut self, page: &PageTokens, chronological: bool) {
+        match self.seek {
+            Seek::Newer => {
+                if chronological && page.first.is_some() {
+                    self.anchor = page.first.clone();
+                }
+            }
+            Seek::Older => {
+                self.seen_count += page.received;
+                self.anchor = page.after.clone();
+                self.exhausted = page.after.is_none();
+            }
         }
     }
+
+    fn url_args(&self) -> String {
+        let mut args = vec![format!("limit={}", self.limit)];
+        match (&self.seek, &self.anchor) {
+            (Seek::Newer, Some(anchor)) => args.push(format!("before={}", anchor)),
+            (Seek::Older, Some(anchor)) => {
+                args.push(format!("after={}", anchor));
+                args.push(format!("count={}", self.seen_count));
+            }
+            _ => (),
+        }
+        format!("?{}", args.join("&"))
+    }
 }
 
 #[derive(PartialEq, Debug, Clone, Eq, Hash)]
@@ -299,7 +728,6 @@ pub enum Listing {
         paginator: Pagination,
     },
 
-    // TODO: Implement pagination system for these other listing categories.
     Hot {
         subreddit: Subreddit,
         paginator: Pagination,
@@ -308,88 +736,110 @@ pub enum Listing {
         subreddit: Subreddit,
         paginator: Pagination,
     },
-    Sort {
+    Top {
         subreddit: Subreddit,
         paginator: Pagination,
         time: Time,
     },
-    Random {
+    Controversial {
         subreddit: Subreddit,
+        paginator: Pagination,
+        time: Time,
     },
+    /// A single random post, picked every `every` hours.
+    Random { subreddit: Subreddit, every: u32 },
 }
 
 impl Listing {
-    pub fn from(listing_name: &str, sub: Subreddit) -> Listing {
+    /// Parses a category as returned by `category()`, e.g. `new`, `top/week`
+    /// or `random/6`. Ranked categories default to Reddit's window of a day,
+    /// random posts to one a day.
+    pub fn parse(category: &str, sub: Subreddit) -> Option<Listing> {
         let mut pagination = Pagination::builder();
-        pagination.set_cursor(Post::empty());
         pagination.set_limit(5);
 
-        match listing_name {
-            "hot" => Hot {
+        let (name, arg) = match category.split_once('/') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (category, None),
+        };
+        if !sub.supports(name) {
+            return None;
+        }
+        if name == "random" {
+            let every = match arg {
+                Some(hours) => hours.parse::<u32>().ok().filter(|h| *h > 0)?,
+                None => RANDOM_INTERVAL_DEFAULT,
+            };
+            return Some(Random {
                 subreddit: sub,
-                paginator: pagination.clone(),
+                every,
+            });
+        }
+        let window = match arg {
+            Some(window) => Some(Time::from(window)?),
+            None => None,
+        };
+        let listing = match (name, window) {
+            ("hot", None) => Hot {
+                subreddit: sub,
+                paginator: pagination,
+            },
+            ("new", None) => New {
+                subreddit: sub,
+                paginator: pagination,
             },
-            "new" => New {
+            ("rising", None) => Rising {
                 subreddit: sub,
-                paginator: pagination.clone(),
+                paginator: pagination,
             },
-            "rising" => Rising {
+            ("top", window) => Top {
                 subreddit: sub,
-                paginator: pagination.clone(),
+                paginator: pagination,
+                time: window.unwrap_or(Time::Day),
             },
-            "sort" => Sort {
+            ("controversial", window) => Controversial {
                 subreddit: sub,
-                paginator: pagination.clone(),
+                paginator: pagination,
+                time: window.unwrap_or(Time::Day),
+            },
+            // Stored by earlier versions, which meant the top of the hour.
+            ("sort", None) => Top {
+                subreddit: sub,
+                paginator: pagination,
                 time: Time::Hour,
             },
-            "random" => Random { subreddit: sub },
-            _ => panic!("invalid listing name"),
-        }
+            _ => return None,
+        };
+        Some(listing)
     }
-    pub fn endpoint(&self) -> String {
-        let mut href_buf = String::new();
-        href_buf.push_str("https://oauth.reddit.com");
 
-        match self {
-            Random { subreddit } => {
-                href_buf.push_str(format!("/r/{}/", subreddit.name()).as_str());
-                href_buf.push_str(self.tag());
-            }
-            Sort {
-                subreddit,
-                time,
-                paginator: params,
-            } => {
-                href_buf.push_str(format!("/r/{}/", subreddit.name()).as_str());
-                href_buf.push_str(self.tag());
-                href_buf.push_str(self.url_args(params).as_str());
-            }
-            Hot {
-                subreddit,
+    pub fn endpoint(&self) -> String {
+        let args = match self {
+            Random { .. } => String::new(),
+            Top {
                 paginator: params,
-            } => {
-                href_buf.push_str(format!("/r/{}/", subreddit.name()).as_str());
-                href_buf.push_str(self.tag());
-                href_buf.push_str(self.url_args(params).as_str());
+                time,
+                ..
             }
-            New {
-                subreddit,
+            | Controversial {
                 paginator: params,
-            } => {
-                href_buf.push_str(format!("/r/{}/", subreddit.name()).as_str());
-                href_buf.push_str(self.tag());
-                href_buf.push_str(self.url_args(params).as_str());
+                time,
+                ..
+            } => format!("{}&t={}", self.url_args(params), time.tag()),
+            Hot {
+                paginator: params, ..
             }
-            Rising {
-                subreddit,
-                paginator: params,
-            } => {
-                href_buf.push_str(format!("/r/{}/", subreddit.name()).as_str());
-                href_buf.push_str(self.tag());
-                href_buf.push_str(self.url_args(params).as_str());
+            | New {
+                paginator: params, ..
             }
+            | Rising {
+                paginator: params, ..
+            } => self.url_args(params),
         };
-        href_buf
+        format!(
+            "https://oauth.reddit.com{}",
+            self.subreddit().path(self.tag(), args)
+        )
     }
 
     pub fn subreddit(&self) -> Subreddit {
@@ -397,66 +847,73 @@ impl Listing {
             Hot { subreddit, .. } => subreddit.clone(),
             New { subreddit, .. } => subreddit.clone(),
             Rising { subreddit, .. } => subreddit.clone(),
-            Sort { subreddit, .. } => subreddit.clone(),
-            Random { subreddit } => subreddit.clone(),
+            Top { subreddit, .. } => subreddit.clone(),
+            Controversial { subreddit, .. } => subreddit.clone(),
+            Random { subreddit, .. } => subreddit.clone(),
         }
     }
 
-    pub fn update_paginator_cache(&mut self, with: &VecDeque<Post>) {
-        match self {
-            Random { .. } => (),
-            listing => match &mut listing.paginator().cursor_anchor {
-                Seek::After { cache: posts } => {
-                    posts.clear();
-                    for post in with {
-                        posts.push_back(post.clone());
-                    }
-                }
-                Seek::Back { cache: posts } => {
-                    posts.clear();
-                    for post in with {
-                        posts.push_front(post.clone());
-                    }
-                }
-            },
-        };
+    /// Moves the listing's cursor past a page that was just retrieved.
+    pub fn advance(&mut self, page: &PageTokens) {
+        let chronological = matches!(self, New { .. });
+        if let Some(paginator) = self.paginator() {
+            paginator.advance(page, chronological);
+        }
     }
 
     pub fn result_limit(&mut self) -> u64 {
+        self.paginator().map_or(1, |paginator| paginator.limit)
+    }
+
+    /// The listing's pagination, which random listings don't have.
+    pub fn paginator(&mut self) -> Option<&mut Pagination> {
         match self {
-            Random { .. } => 1,
-            listing => listing.paginator().limit,
+            Hot { paginator, .. }
+            | New { paginator, .. }
+            | Rising { paginator, .. }
+            | Top { paginator, .. }
+            | Controversial { paginator, .. } => Some(paginator),
+            Random { .. } => None,
         }
     }
 
-    pub fn reset_cursor(&mut self) {
-        match &mut self.paginator().cursor_anchor {
-            Seek::After { cache: posts } => {
-                posts.push_front(Post::empty());
-            }
-            Seek::Back { cache: posts } => {
-                posts.push_back(Post::empty());
-            }
-        };
+    /// Which way the listing is walked; random posts have no direction and
+    /// count as polling.
+    pub fn seek(&self) -> Seek {
+        match self {
+            Hot { paginator, .. }
+            | New { paginator, .. }
+            | Rising { paginator, .. }
+            | Top { paginator, .. }
+            | Controversial { paginator, .. } => *paginator.cursor(),
+            Random { .. } => Seek::Newer,
+        }
     }
 
-    pub fn paginator(&mut self) -> &mut Pagination {
+    /// Where polling left off, saved so it can resume there after a restart.
+    pub fn anchor(&self) -> Option<String> {
         match self {
-            Hot {
-                paginator: params, ..
-            } => params,
-            New {
-                paginator: params, ..
-            } => params,
-            Rising {
-                paginator: params, ..
-            } => params,
-            Sort {
-                paginator: params, ..
-            } => params,
-            Random { .. } => {
-                panic!("pagination does not exist for `SubredditListing::Random`")
-            }
+            Hot { paginator, .. }
+            | New { paginator, .. }
+            | Rising { paginator, .. }
+            | Top { paginator, .. }
+            | Controversial { paginator, .. } => paginator.anchor().cloned(),
+            Random { .. } => None,
+        }
+    }
+
+    /// Continues polling from a saved anchor.
+    pub fn resume(&mut self, anchor: String) {
+        if let Some(paginator) = self.paginator() {
+            paginator.set_anchor(anchor);
+        }
+    }
+
+    /// How long to wait between picks of a random listing.
+    pub fn random_interval(&self) -> Option<Duration> {
+        match self {
+            Random { every, .. } => Some(Duration::from_secs(*every as u64 * 60 * 60)),
+            _ => None,
         }
     }
 
@@ -466,52 +923,226 @@ impl Listing {
             New { .. } => "new",
             Random { .. } => "random",
             Rising { .. } => "rising",
-            Sort { .. } => "sort",
+            Top { .. } => "top",
+            Controversial { .. } => "controversial",
         }
     }
 
-    fn url_args(&self, pagination_arg: &Pagination) -> String {
-        let mut buf = String::new();
-
-        let seek_args = |new_posts: &VecDeque<Post>, direction| {
-            let mut args = String::new();
-            if new_posts.len() == 0 {
-                args.push_str(format!("?{}={}", direction, "null").as_str());
-            } else {
-                let post = new_posts.get(new_posts.len() - 1).unwrap();
-                let cursor_arg = if post.id().is_empty() {
-                    "null".to_string()
-                } else {
-                    format!("t3_{}", post.id().to_string())
-                };
-                args.push_str(format!("?{}={}", direction, cursor_arg).as_str());
-            }
-            args
-        };
-
-        match &pagination_arg.cursor_anchor {
-            Seek::Back { cache: posts } => {
-                let args = seek_args(posts, "after");
-                buf.push_str(args.as_str())
+    /// Identifies the listing within its subreddit, including the window of
+    /// ranked listings, e.g. `top/week`.
+    pub fn category(&self) -> String {
+        match self {
+            Top { time, .. } | Controversial { time, .. } => {
+                format!("{}/{}", self.tag(), time.tag())
             }
-            Seek::After { cache: posts } => {
-                let args = seek_args(posts, "before");
-                buf.push_str(args.as_str())
+            Random { every, .. } if *every != RANDOM_INTERVAL_DEFAULT => {
+                format!("{}/{}", self.tag(), every)
             }
-        };
-        buf.push_str(
-            format!(
-                "&count={}&limit={}&show={}",
-                pagination_arg.seen_count, pagination_arg.limit, pagination_arg.show_rules
-            )
-            .as_str(),
-        );
-        buf
+            listing => listing.tag().to_string(),
+        }
+    }
+
+    fn url_args(&self, pagination_arg: &Pagination) -> String {
+        pagination_arg.url_args()
     }
 }
 
 impl ToString for Listing {
     fn to_string(&self) -> String {
-        self.tag().to_string()
+        self.category()
+    }
+}
+
+#[test]
+fn test_grant_params() {
+    let token = BearerToken {
+        token: "access".to_string(),
+        refresh_token: Some("refresh".to_string()),
+        expires: Instant::now(),
+    };
+
+    let grant = Grant::InstalledClient {
+        device_id: "device".to_string(),
+    };
+    assert_eq!(
+        grant.params(AuthTokenAction::New, None),
+        vec![
+            (
+                "grant_type",
+                "https://oauth.reddit.com/grants/installed_client".to_string()
+            ),
+            ("device_id", "device".to_string()),
+        ]
+    );
+
+    // Refreshing sends the refresh token, never the access token.
+    let grant = Grant::AuthorizationCode {
+        code: Some("code".to_string()),
+        redirect_uri: "http://localhost".to_string(),
+        refresh_token: None,
+    };
+    assert_eq!(
+        grant.params(AuthTokenAction::Refresh, Some(&token)),
+        vec![
+            ("grant_type", "refresh_token".to_string()),
+            ("refresh_token", "refresh".to_string()),
+        ]
+    );
+    assert_eq!(
+        grant.params(AuthTokenAction::New, None)[0],
+        ("grant_type", "authorization_code".to_string())
+    );
+
+    // Tokens from application-only grants can't be refreshed.
+    assert_eq!(
+        Grant::ClientCredentials.params(AuthTokenAction::Refresh, None),
+        vec![("grant_type", "client_credentials".to_string())]
+    );
+}
+
+#[test]
+fn test_listing_response() {
+    let page = r#"{
+        "kind": "Listing",
+        "data": {
+            "after": "t3_b",
+            "children": [
+                {"kind": "t3", "data": {
+                    "id": "b", "name": "t3_b", "title": "Caf\u00e9 \"at night\"", "author": "painter",
+                    "url": "https://i.redd.it/b.jpg", "ups": 12, "downs": 0,
+                    "created_utc": 1684800000.0
+                }},
+                {"kind": "t1", "data": {"id": "comment"}},
+                {"kind": "t3", "data": {"id": "a", "url": null}}
+            ]
+        }
+    }"#;
+    let page = serde_json::from_str::<ListingResponse>(page).unwrap();
+    let (posts, page) = page.into_page(5);
+
+    assert_eq!(posts.len(), 2);
+    assert_eq!(page.after.as_deref(), Some("t3_b"));
+    assert_eq!(posts[0].id(), "a");
+    assert_eq!(posts[0].author, "[deleted]");
+    assert_eq!(posts[1].title, "Café \"at night\"");
+    assert_eq!(posts[1].ups, 12);
+    assert_eq!(
+        posts[1].posted_at,
+        Utc.timestamp_opt(1684800000, 0).single()
+    );
+}
+
+#[test]
+fn test_pagination() {
+    let page = PageTokens {
+        first: Some("t3_new".to_string()),
+        after: Some("t3_old".to_string()),
+        received: 5,
+    };
+
+    let mut listing = Listing::parse("new", "Art".into()).unwrap();
+    assert_eq!(
+        listing.endpoint(),
+        "https://oauth.reddit.com/r/Art/new?limit=5"
+    );
+    listing.advance(&page);
+    assert_eq!(
+        listing.endpoint(),
+        "https://oauth.reddit.com/r/Art/new?limit=5&before=t3_new"
+    );
+
+    // Ranked listings are re-read from the top when polling.
+    let mut listing = Listing::parse("hot", "Art".into()).unwrap();
+    listing.advance(&page);
+    assert_eq!(
+        listing.endpoint(),
+        "https://oauth.reddit.com/r/Art/hot?limit=5"
+    );
+
+    listing.paginator().unwrap().seek_back();
+    listing.advance(&page);
+    listing.advance(&page);
+    assert_eq!(
+        listing.endpoint(),
+        "https://oauth.reddit.com/r/Art/hot?limit=5&after=t3_old&count=10"
+    );
+    listing.advance(&PageTokens::default());
+    assert!(listing.paginator().unwrap().is_exhausted());
+    assert!(Listing::parse("random", "Art".into())
+        .unwrap()
+        .paginator()
+        .is_none());
+}
+
+#[test]
+fn test_ranked_listings() {
+    let listing = Listing::parse("top/week", "Art".into()).unwrap();
+    assert_eq!(listing.category(), "top/week");
+    assert_eq!(
+        listing.endpoint(),
+        "https://oauth.reddit.com/r/Art/top?limit=5&t=week"
+    );
+
+    let listing = Listing::parse("controversial", "Art".into()).unwrap();
+    assert_eq!(listing.category(), "controversial/day");
+    assert_eq!(
+        Listing::parse("sort", "Art".into()).unwrap().category(),
+        "top/hour"
+    );
+    assert!(Listing::parse("top/fortnight", "Art".into()).is_none());
+    assert!(Listing::parse("new/week", "Art".into()).is_none());
+}
+
+#[test]
+fn test_listing_targets() {
+    let endpoint = |category, target: &str| {
+        Listing::parse(category, Subreddit::from(target)).map(|listing| listing.endpoint())
+    };
+
+    assert_eq!(
+        endpoint("new", "r/Art+Painting").unwrap(),
+        "https://oauth.reddit.com/r/Art+Painting/new?limit=5"
+    );
+    assert_eq!(
+        endpoint("top/week", "u/someone").unwrap(),
+        "https://oauth.reddit.com/user/someone/submitted?limit=5&t=week&sort=top"
+    );
+    assert_eq!(
+        endpoint("hot", "m/someone/paintings").unwrap(),
+        "https://oauth.reddit.com/user/someone/m/paintings/hot?limit=5"
+    );
+    assert_eq!(
+        endpoint("new", "Art/search:oil+on+canvas").unwrap(),
+        "https://oauth.reddit.com/r/Art/search?limit=5&q=oil%20on%20canvas&sort=new&restrict_sr=1&type=link"
+    );
+    assert!(endpoint("rising", "u/someone").is_none());
+    assert_eq!(
+        Subreddit::from("search:oil+on+canvas").to_string(),
+        "search \"oil on canvas\""
+    );
+}
+
+#[test]
+fn test_random_listing() {
+    let mut listing = Listing::parse("random/6", "Art".into()).unwrap();
+    assert_eq!(listing.category(), "random/6");
+    assert_eq!(listing.seek(), Seek::Newer);
+    assert_eq!(listing.result_limit(), 1);
+    assert_eq!(listing.endpoint(), "https://oauth.reddit.com/r/Art/random");
+    assert_eq!(
+        Listing::parse("random", "Art".into()).unwrap().category(),
+        "random"
+    );
+    assert!(Listing::parse("random/0", "Art".into()).is_none());
+
+    let post = r#"[
+        {"kind": "Listing", "data": {"children": [
+            {"kind": "t3", "data": {"id": "a", "url": "https://i.redd.it/a.jpg"}}
+        ]}},
+        {"kind": "Listing", "data": {"children": [{"kind": "t1", "data": {"id": "c"}}]}}
+    ]"#;
+    match serde_json::from_str::<ListingBody>(post).unwrap() {
+        ListingBody::Post(pages) => assert_eq!(pages[0].data.children.len(), 1),
+        ListingBody::Page(_) => panic!("expected the array of a random post"),
     }
 }
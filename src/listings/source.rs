@@ -1,10 +1,73 @@
 use async_trait::async_trait;
 use std::collections::VecDeque;
+use std::time::Duration;
 
 use crate::content::Post;
 use crate::listings::reddit::Listing;
 
+/// Why a listing couldn't be retrieved.
+#[derive(Debug)]
+pub enum SourceError {
+    /// Credentials were missing, rejected or the token couldn't be renewed.
+    Auth(String),
+    /// The listing doesn't exist, e.g. a deleted or banned subreddit.
+    NotFound,
+    /// The listing exists but can't be read, e.g. a private subreddit.
+    Forbidden,
+    /// The source asked to slow down for `retry_after`.
+    RateLimited { retry_after: Duration },
+    /// The response didn't have the expected shape.
+    Parse(String),
+    /// Network errors and unexpected statuses.
+    Transport(reqwest::Error),
+}
+
+impl SourceError {
+    /// Whether retrying the listing later can't succeed.
+    pub fn is_gone(&self) -> bool {
+        matches!(self, SourceError::NotFound | SourceError::Forbidden)
+    }
+}
+
+impl std::fmt::Display for SourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SourceError::Auth(reason) => write!(f, "authentication failed: {}", reason),
+            SourceError::NotFound => write!(f, "listing not found"),
+            SourceError::Forbidden => write!(f, "listing is private or restricted"),
+            SourceError::RateLimited { retry_after } => {
+                write!(f, "rate limited, retry in {}s", retry_after.as_secs())
+            }
+            SourceError::Parse(reason) => write!(f, "unexpected response: {}", reason),
+            SourceError::Transport(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SourceError {}
+
+impl From<reqwest::Error> for SourceError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_decode() {
+            SourceError::Parse(e.to_string())
+        } else {
+            SourceError::Transport(e)
+        }
+    }
+}
+
 #[async_trait]
 pub trait ListingSource: Default + Send + Sync + Clone + 'static {
-    async fn retrieve_posts(&mut self, listing: &mut Listing) -> reqwest::Result<VecDeque<Post>>;
+    fn name(&self) -> &'static str;
+
+    /// How often the source can currently be queried, across every listing,
+    /// without exceeding its API budget.
+    fn request_spacing(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    async fn retrieve_posts(
+        &mut self,
+        listing: &mut Listing,
+    ) -> Result<VecDeque<Post>, SourceError>;
 }
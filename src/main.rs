@@ -1,37 +1,98 @@
 use std::sync::Arc;
+use std::{env, process};
 
 use dotenvy::dotenv;
-use log::info;
+use log::{error, info};
 use teloxide::dispatching::{HandlerExt, UpdateFilterExt};
 use teloxide::prelude::{Dispatcher, Update};
 use teloxide::{dptree, Bot};
 use tokio::sync::Mutex;
 
-use crate::aggregator::AggregatorStore;
-use crate::auth::ClientManager;
+use crate::aggregator::Listeners;
+use crate::config::Config;
+use crate::curator::Curator;
+use crate::db::Storage;
+use crate::delivery::{DeliveryThrottle, DigestScheduler};
+use crate::listings::reddit::Api;
+use crate::outbound::Outbox;
+use crate::retry::RetryWorker;
 use crate::telegram::{ConfCommand, SubscribeCommand};
 
 mod aggregator;
 mod artvault;
 mod auth;
+mod backfill;
+mod config;
 mod content;
 mod curator;
+mod db;
+mod delivery;
 mod filters;
 mod imgproc;
 mod listings;
+mod outbound;
+mod polling;
+mod retry;
 mod schema;
+mod seen;
+mod supervisor;
 mod telegram;
+#[cfg(test)]
+mod testing;
 
 #[tokio::main]
 async fn main() {
-    dotenv().expect("include .env");
+    dotenv().ok();
     pretty_env_logger::init();
     info!("Starting command bot...");
 
-    let bot = Bot::from_env();
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Refusing to start: {}", e);
+            process::exit(1);
+        }
+    };
+    let pool = db::connect(&config.database.url);
+    match db::migrate(&pool) {
+        Ok(0) => info!("Database schema is up to date"),
+        Ok(n) => info!("Applied {} pending migration(s)", n),
+        Err(e) => {
+            error!("Refusing to start: {}", e);
+            process::exit(1);
+        }
+    }
+    if env::args().any(|arg| arg == "--migrate-only") {
+        return;
+    }
 
-    let store = Arc::new(Mutex::new(AggregatorStore::instance()));
-    let clients = Arc::new(Mutex::new(ClientManager::instance()));
+    let bot = Bot::new(&config.telegram.token);
+    let credentials = config
+        .reddit
+        .credentials()
+        .expect("checked by Config::load");
+    let storage: Arc<dyn Storage> = Arc::new(pool.clone());
+    let curator = Curator::from(Api::from(&reqwest::Client::new(), credentials), &pool)
+        .with_limits(config.polling);
+    let outbox = Outbox::spawn(bot.clone(), storage.vault(), config.delivery);
+    let throttle = Arc::new(Mutex::new(DeliveryThrottle::default()));
+    tokio::spawn(DigestScheduler::from(outbox.clone(), throttle.clone(), &pool).run());
+    tokio::spawn(RetryWorker::from(outbox.clone(), &pool).run());
+
+    let listeners = Listeners::default();
+    match telegram::resume(
+        storage.clone(),
+        throttle.clone(),
+        outbox.clone(),
+        &curator,
+        &listeners,
+        pool.clone(),
+    )
+    .await
+    {
+        Ok(n) => info!("Resumed {} subscription(s)", n),
+        Err(e) => error!("couldn't resume subscriptions: {}", e),
+    }
 
     let handler = Update::filter_message()
         .branch(
@@ -42,13 +103,23 @@ async fn main() {
         .branch(
             dptree::entry()
                 .filter_command::<SubscribeCommand>()
-                .endpoint(telegram::listen_silence_handler),
+                .endpoint(telegram::listen_silence_handler::<Api>),
         );
 
     Dispatcher::builder(bot, handler)
         .enable_ctrlc_handler()
-        .dependencies(dptree::deps![store, clients])
+        .dependencies(dptree::deps![
+            storage,
+            throttle,
+            outbox,
+            curator.clone(),
+            listeners,
+            pool,
+            Arc::new(config)
+        ])
         .build()
         .dispatch()
         .await;
+
+    curator.shutdown().await;
 }
@@ -0,0 +1,706 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::join_all;
+use futures::stream::FuturesUnordered;
+use futures::{Future, StreamExt};
+use log::{error, warn};
+use reqwest::{StatusCode, Url};
+use serde::Deserialize;
+use teloxide::payloads::{SendMessageSetters, SendPhotoSetters};
+use teloxide::prelude::*;
+use teloxide::types::{InputFile, InputMedia, InputMediaPhoto, ParseMode};
+use teloxide::{ApiError, RequestError};
+use tokio::spawn;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::spawn_blocking;
+use tokio::time::{sleep_until, Instant};
+
+use crate::artvault::ArtVault;
+use crate::content::Post;
+use crate::db;
+use crate::imgproc::{self, PhotoError};
+use crate::retry::Failure;
+
+// Telegram allows bots about 30 messages per second overall, one per second
+// to the same chat (short bursts are tolerated) and 20 per minute to the
+// same group.
+const GLOBAL_RATE: f64 = 30.0;
+const CHAT_RATE: f64 = 1.0;
+const CHAT_BURST: f64 = 3.0;
+const GROUP_RATE_PER_MINUTE: f64 = 20.0;
+const GROUP_BURST: f64 = 3.0;
+
+// Telegram refuses media groups with more than 10 items, and uploaded
+// documents over 50MB.
+const MEDIA_GROUP_MAX: usize = 10;
+const DOCUMENT_SIZE_MAX: usize = 50 * 1024 * 1024;
+
+/// Messages waiting to be sent before `Outbox` callers start waiting too.
+pub const OUTBOX_CAPACITY: usize = 64;
+
+pub const SEND_RETRIES_MAX: u32 = 5;
+
+/// How many messages the outbox sends, at most, overall and to one chat.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SendLimits {
+    pub global_per_second: f64,
+    pub chat_per_second: f64,
+    pub group_per_minute: f64,
+}
+
+impl Default for SendLimits {
+    fn default() -> Self {
+        Self {
+            global_per_second: GLOBAL_RATE,
+            chat_per_second: CHAT_RATE,
+            group_per_minute: GROUP_RATE_PER_MINUTE,
+        }
+    }
+}
+
+struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: f64, rate: f64) -> Self {
+        Self {
+            capacity,
+            rate,
+            tokens: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    /// How long until `cost` tokens can be taken. Costs above the capacity
+    /// only need a full bucket and leave it in debt once taken.
+    fn wait_for(&mut self, cost: f64, now: Instant) -> Duration {
+        self.refill(now);
+        let needed = cost.min(self.capacity) - self.tokens;
+        if needed <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(needed / self.rate)
+        }
+    }
+
+    fn take(&mut self, cost: f64, now: Instant) {
+        self.refill(now);
+        self.tokens -= cost;
+    }
+
+    /// Empties the bucket so nothing can be taken for at least `wait`.
+    fn pause(&mut self, wait: Duration, now: Instant) {
+        self.refill(now);
+        self.tokens = self.tokens.min(0.0) - wait.as_secs_f64() * self.rate;
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Outgoing {
+    Text(String),
+    Photo(Post),
+    Album(Vec<Post>),
+}
+
+impl Outgoing {
+    fn cost(&self) -> f64 {
+        match self {
+            Outgoing::Album(posts) => posts.len() as f64,
+            _ => 1.0,
+        }
+    }
+
+    fn posts(&self) -> &[Post] {
+        match self {
+            Outgoing::Text(_) => &[],
+            Outgoing::Photo(post) => std::slice::from_ref(post),
+            Outgoing::Album(posts) => posts,
+        }
+    }
+}
+
+struct Envelope {
+    chat: ChatId,
+    content: Outgoing,
+    attempts: u32,
+    reply: oneshot::Sender<ResponseResult<()>>,
+}
+
+/// Telegram's file_ids for the media of a message being sent: those cached
+/// beforehand, and what the send revealed about them.
+#[derive(Default)]
+struct FileIds {
+    cached: HashMap<String, String>,
+    learnt: Vec<(String, String)>,
+    stale: Vec<String>,
+}
+
+impl FileIds {
+    /// Looks up the file_ids Telegram already has for the media of `posts`.
+    async fn lookup(vault: SharedVault, posts: &[Post]) -> Self {
+        let hrefs = posts
+            .iter()
+            .map(|post| post.media_href.to_string())
+            .collect::<Vec<_>>();
+        let cached = db::blocking(move || {
+            let mut vault = vault.lock().unwrap();
+            hrefs
+                .into_iter()
+                .filter_map(|href| vault.file_id(&href).map(|id| (href, id)))
+                .collect()
+        })
+        .await;
+        Self {
+            cached,
+            ..Default::default()
+        }
+    }
+
+    /// Saves what the send revealed, so the next one uses working file_ids.
+    async fn remember(self, vault: SharedVault) {
+        if self.stale.is_empty() && self.learnt.is_empty() {
+            return;
+        }
+        db::blocking(move || {
+            let mut vault = vault.lock().unwrap();
+            for href in self.stale.iter() {
+                vault.forget_file_id(href);
+            }
+            for (href, id) in self.learnt.iter() {
+                vault.save_file_id(href, id);
+            }
+        })
+        .await;
+    }
+
+    fn learn(&mut self, post: &Post, msg: &Message) {
+        // Sizes are listed smallest first, the last being the original.
+        let id = match msg.photo().and_then(|sizes| sizes.last()) {
+            Some(size) => size.file.id.to_string(),
+            None => return,
+        };
+        if self.cached.get(&post.media_href) != Some(&id) {
+            self.learnt.push((post.media_href.to_string(), id));
+        }
+    }
+}
+
+type InFlight = Pin<Box<dyn Future<Output = (Envelope, ResponseResult<()>)> + Send>>;
+
+type SharedVault = Arc<std::sync::Mutex<Box<dyn ArtVault>>>;
+
+/// Handle to the single task every outgoing post goes through. Sends are
+/// paced to Telegram's flood limits, retried when Telegram asks to wait, and
+/// callers wait once too much is queued, which holds back the curators
+/// feeding them. Media Telegram already has is sent by its file_id, and
+/// media Telegram can't fetch by url is downloaded and uploaded instead.
+#[derive(Clone)]
+pub struct Outbox {
+    tx: mpsc::Sender<Envelope>,
+}
+
+impl Outbox {
+    pub fn spawn(bot: Bot, vault: Box<dyn ArtVault>, limits: SendLimits) -> Self {
+        let (tx, rx) = mpsc::channel(OUTBOX_CAPACITY);
+        spawn(Dispatcher::new(bot, rx, vault, limits).run());
+        Self { tx }
+    }
+
+    /// Sends a single post as a captioned photo. Posts without a valid media
+    /// url can never be delivered, so they're skipped rather than reported.
+    pub async fn send_post(&self, chat: ChatId, post: &Post) -> ResponseResult<()> {
+        if Url::parse(post.media_href.as_str()).is_err() {
+            warn!(
+                "Skipping PostID \"{}\" with invalid media url: {}",
+                post.id(),
+                post.media_href
+            );
+            return Ok(());
+        }
+        self.submit(chat, Outgoing::Photo(post.clone())).await
+    }
+
+    /// Sends a notice, formatted as HTML.
+    pub async fn send_text(&self, chat: ChatId, text: String) -> ResponseResult<()> {
+        self.submit(chat, Outgoing::Text(text)).await
+    }
+
+    /// Sends `header` followed by `posts` batched into media groups.
+    pub async fn send_digest(
+        &self,
+        chat: ChatId,
+        header: String,
+        posts: &[Post],
+    ) -> ResponseResult<()> {
+        let posts = posts
+            .iter()
+            .filter(|post| Url::parse(post.media_href.as_str()).is_ok())
+            .cloned()
+            .collect::<Vec<_>>();
+
+        self.submit(chat, Outgoing::Text(header)).await?;
+        for group in posts.chunks(MEDIA_GROUP_MAX) {
+            let content = if group.len() == 1 {
+                Outgoing::Photo(group[0].clone())
+            } else {
+                Outgoing::Album(group.to_vec())
+            };
+            self.submit(chat, content).await?;
+        }
+        Ok(())
+    }
+
+    async fn submit(&self, chat: ChatId, content: Outgoing) -> ResponseResult<()> {
+        let (reply, rx) = oneshot::channel();
+        let envelope = Envelope {
+            chat,
+            content,
+            attempts: 0,
+            reply,
+        };
+        if self.tx.send(envelope).await.is_err() {
+            return Err(dispatcher_gone("Outbox dispatcher stopped"));
+        }
+        rx.await
+            .unwrap_or_else(|_| Err(dispatcher_gone("Outbox dispatcher dropped a message")))
+    }
+}
+
+fn dispatcher_gone(reason: &str) -> RequestError {
+    error!("{}", reason);
+    RequestError::Io(std::io::Error::new(std::io::ErrorKind::BrokenPipe, reason))
+}
+
+struct Dispatcher {
+    bot: Bot,
+    http: reqwest::Client,
+    vault: SharedVault,
+    rx: mpsc::Receiver<Envelope>,
+    pending: HashMap<ChatId, VecDeque<Envelope>>,
+    queued: usize,
+    busy: HashSet<ChatId>,
+    chats: HashMap<ChatId, TokenBucket>,
+    global: TokenBucket,
+    limits: SendLimits,
+}
+
+impl Dispatcher {
+    fn new(
+        bot: Bot,
+        rx: mpsc::Receiver<Envelope>,
+        vault: Box<dyn ArtVault>,
+        limits: SendLimits,
+    ) -> Self {
+        Self {
+            bot,
+            http: reqwest::Client::new(),
+            vault: Arc::new(std::sync::Mutex::new(vault)),
+            rx,
+            pending: HashMap::new(),
+            queued: 0,
+            busy: HashSet::new(),
+            chats: HashMap::new(),
+            global: TokenBucket::new(limits.global_per_second, limits.global_per_second),
+            limits,
+        }
+    }
+
+    async fn run(mut self) {
+        let mut in_flight: FuturesUnordered<InFlight> = FuturesUnordered::new();
+        let mut closed = false;
+
+        loop {
+            let wake = self.dispatch_ready(&mut in_flight);
+            if closed && self.queued == 0 && in_flight.is_empty() {
+                break;
+            }
+
+            tokio::select! {
+                envelope = self.rx.recv(), if !closed && self.queued < OUTBOX_CAPACITY => {
+                    match envelope {
+                        Some(envelope) => {
+                            self.queued += 1;
+                            self.pending.entry(envelope.chat).or_default().push_back(envelope);
+                        }
+                        None => closed = true,
+                    }
+                }
+                Some((envelope, res)) = in_flight.next(), if !in_flight.is_empty() => {
+                    self.settle(envelope, res);
+                }
+                _ = sleep_until(wake.unwrap_or_else(Instant::now)), if wake.is_some() => {}
+            }
+        }
+    }
+
+    /// Starts sending the head of every chat queue the rate limits allow,
+    /// one message in flight per chat so chats see posts in order. Returns
+    /// when the next message held back by the limits can go.
+    fn dispatch_ready(&mut self, in_flight: &mut FuturesUnordered<InFlight>) -> Option<Instant> {
+        let now = Instant::now();
+        let mut wake: Option<Instant> = None;
+
+        for (chat, queue) in self.pending.iter_mut() {
+            if self.busy.contains(chat) {
+                continue;
+            }
+            let cost = match queue.front() {
+                Some(envelope) => envelope.content.cost(),
+                None => continue,
+            };
+            let limits = &self.limits;
+            let bucket = self.chats.entry(*chat).or_insert_with(|| {
+                if chat.is_group() || chat.is_channel_or_supergroup() {
+                    TokenBucket::new(GROUP_BURST, limits.group_per_minute / 60.0)
+                } else {
+                    TokenBucket::new(CHAT_BURST, limits.chat_per_second)
+                }
+            });
+            let wait = bucket
+                .wait_for(cost, now)
+                .max(self.global.wait_for(cost, now));
+            if !wait.is_zero() {
+                let at = now + wait;
+                wake = Some(wake.map_or(at, |w| w.min(at)));
+                continue;
+            }
+
+            bucket.take(cost, now);
+            self.global.take(cost, now);
+
+            let mut envelope = queue.pop_front().unwrap();
+            envelope.attempts += 1;
+            self.busy.insert(*chat);
+
+            let (bot, http, vault) = (self.bot.clone(), self.http.clone(), self.vault.clone());
+            in_flight.push(Box::pin(async move {
+                // The vault is read and written off the dispatcher's thread,
+                // along with the send.
+                let mut files = FileIds::lookup(vault.clone(), envelope.content.posts()).await;
+                let res = deliver(&bot, &http, envelope.chat, &envelope.content, &mut files).await;
+                files.remember(vault).await;
+                (envelope, res)
+            }));
+        }
+        wake
+    }
+
+    fn settle(&mut self, envelope: Envelope, res: ResponseResult<()>) {
+        self.busy.remove(&envelope.chat);
+        let queue = self.pending.entry(envelope.chat).or_default();
+
+        if let Err(RequestError::RetryAfter(wait)) = &res {
+            if envelope.attempts < SEND_RETRIES_MAX {
+                warn!(
+                    "Flood limit hit for ChatID: '{}', retrying in {}s",
+                    envelope.chat,
+                    wait.as_secs()
+                );
+                // Flood waits apply to the whole bot, not just this chat.
+                let now = Instant::now();
+                self.global.pause(*wait, now);
+                if let Some(bucket) = self.chats.get_mut(&envelope.chat) {
+                    bucket.pause(*wait, now);
+                }
+                queue.push_front(envelope);
+                return;
+            }
+        }
+
+        if queue.is_empty() {
+            self.pending.remove(&envelope.chat);
+        }
+        self.queued -= 1;
+        if let Err(e) = &res {
+            error!("couldn't send to ChatID: '{}': {}", envelope.chat, e);
+        }
+        let _ = envelope.reply.send(res);
+    }
+}
+
+async fn deliver(
+    bot: &Bot,
+    http: &reqwest::Client,
+    chat: ChatId,
+    content: &Outgoing,
+    files: &mut FileIds,
+) -> ResponseResult<()> {
+    match content {
+        Outgoing::Text(text) => {
+            bot.send_message(chat, text)
+                .parse_mode(ParseMode::Html)
+                .await?;
+        }
+        Outgoing::Photo(post) => {
+            if let Some(id) = files.cached.get(&post.media_href).cloned() {
+                let res = bot
+                    .send_photo(chat, InputFile::file_id(id))
+                    .caption(caption(post))
+                    .parse_mode(ParseMode::Html)
+                    .await;
+                match res {
+                    Ok(_) => return Ok(()),
+                    Err(e) if Failure::classify(&e) == Failure::MediaRejected => {
+                        warn!(
+                            "Cached file_id of PostID: '{}' was rejected ({}), sending its url",
+                            post.id(),
+                            e
+                        );
+                        files.stale.push(post.media_href.to_string());
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+
+            let res = bot
+                .send_photo(chat, media_file(post))
+                .caption(caption(post))
+                .parse_mode(ParseMode::Html)
+                .await;
+            match res {
+                Ok(msg) => files.learn(post, &msg),
+                Err(e) if Failure::classify(&e) == Failure::MediaRejected => {
+                    warn!(
+                        "Telegram couldn't fetch PostID: '{}' ({}), uploading it instead",
+                        post.id(),
+                        e
+                    );
+                    let upload = prepare_upload(http, post).await?;
+                    let msg = send_upload(bot, chat, post, upload).await?;
+                    files.learn(post, &msg);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Outgoing::Album(posts) => {
+            let mut use_cached = posts
+                .iter()
+                .any(|post| files.cached.contains_key(&post.media_href));
+            loop {
+                let media = posts.iter().map(|post| {
+                    let file = match files.cached.get(&post.media_href) {
+                        Some(id) if use_cached => InputFile::file_id(id),
+                        _ => media_file(post),
+                    };
+                    InputMedia::Photo(
+                        InputMediaPhoto::new(file)
+                            .caption(caption(post))
+                            .parse_mode(ParseMode::Html),
+                    )
+                });
+                match bot.send_media_group(chat, media).await {
+                    Ok(messages) => {
+                        for (post, msg) in posts.iter().zip(messages.iter()) {
+                            files.learn(post, msg);
+                        }
+                    }
+                    Err(e) if Failure::classify(&e) == Failure::MediaRejected && use_cached => {
+                        warn!(
+                            "Cached file_ids of a media group were rejected ({}), sending urls",
+                            e
+                        );
+                        for post in posts {
+                            if files.cached.contains_key(&post.media_href) {
+                                files.stale.push(post.media_href.to_string());
+                            }
+                        }
+                        use_cached = false;
+                        continue;
+                    }
+                    Err(e) if Failure::classify(&e) == Failure::MediaRejected => {
+                        warn!(
+                            "Telegram couldn't fetch a media group ({}), uploading it instead",
+                            e
+                        );
+                        upload_album(bot, http, chat, posts, files).await?;
+                    }
+                    Err(e) => return Err(e),
+                }
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Media downloaded because Telegram couldn't fetch it from its url.
+enum Upload {
+    /// Re-encoded within Telegram's photo limits.
+    Photo(Vec<u8>),
+    /// The original file, for images that can't be made to fit.
+    Document(Vec<u8>, String),
+}
+
+async fn prepare_upload(http: &reqwest::Client, post: &Post) -> ResponseResult<Upload> {
+    let resp = http.get(post.media_href.as_str()).send().await?;
+    let status = resp.status();
+    // Media that's gone or forbidden stays that way, unlike server errors.
+    if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS {
+        warn!(
+            "couldn't download the media of PostID: '{}': {}",
+            post.id(),
+            status
+        );
+        return Err(RequestError::Api(ApiError::FailedToGetUrlContent));
+    }
+    let resp = resp.error_for_status()?;
+    if resp.content_length().unwrap_or(0) as usize > DOCUMENT_SIZE_MAX {
+        return Err(RequestError::Api(ApiError::RequestEntityTooLarge));
+    }
+    let original = resp.bytes().await?.to_vec();
+    if original.len() > DOCUMENT_SIZE_MAX {
+        return Err(RequestError::Api(ApiError::RequestEntityTooLarge));
+    }
+
+    // A malformed image mustn't take the dispatcher, and every chat's
+    // deliveries, down with it.
+    let (original, photo) = spawn_blocking(move || {
+        let photo = imgproc::fit_photo(&original);
+        (original, photo)
+    })
+    .await
+    .map_err(|e| {
+        error!(
+            "couldn't process the image of PostID: '{}': {}",
+            post.id(),
+            e
+        );
+        RequestError::Api(ApiError::ImageProcessFailed)
+    })?;
+
+    Ok(match photo {
+        Ok(photo) => Upload::Photo(photo),
+        Err(PhotoError::Undecodable) => {
+            warn!(
+                "The media of PostID: '{}' isn't an image, not sending it",
+                post.id()
+            );
+            return Err(RequestError::Api(ApiError::ImageProcessFailed));
+        }
+        Err(PhotoError::WontFit) => {
+            let name = Url::parse(post.media_href.as_str())
+                .ok()
+                .and_then(|url| url.path_segments()?.next_back().map(String::from))
+                .filter(|name| !name.is_empty())
+                .unwrap_or_else(|| post.id().to_string());
+            Upload::Document(original, name)
+        }
+    })
+}
+
+async fn send_upload(
+    bot: &Bot,
+    chat: ChatId,
+    post: &Post,
+    upload: Upload,
+) -> ResponseResult<Message> {
+    match upload {
+        Upload::Photo(photo) => {
+            let file = InputFile::memory(photo).file_name(format!("{}.jpg", post.id()));
+            bot.send_photo(chat, file)
+                .caption(caption(post))
+                .parse_mode(ParseMode::Html)
+                .await
+        }
+        Upload::Document(original, name) => {
+            bot.send_document(chat, InputFile::memory(original).file_name(name))
+                .caption(caption(post))
+                .parse_mode(ParseMode::Html)
+                .await
+        }
+    }
+}
+
+/// Re-sends a media group as uploads. Images that only fit as documents
+/// can't share a group with photos, so they follow it on their own.
+async fn upload_album(
+    bot: &Bot,
+    http: &reqwest::Client,
+    chat: ChatId,
+    posts: &[Post],
+    files: &mut FileIds,
+) -> ResponseResult<()> {
+    let uploads = join_all(posts.iter().map(|post| prepare_upload(http, post))).await;
+
+    let mut photos = vec![];
+    let mut documents = vec![];
+    for (post, upload) in posts.iter().zip(uploads) {
+        match upload {
+            Ok(Upload::Photo(photo)) => photos.push((post, photo)),
+            Ok(document) => documents.push((post, document)),
+            Err(e) => warn!(
+                "couldn't upload PostID: '{}', skipping it: {}",
+                post.id(),
+                e
+            ),
+        }
+    }
+
+    if photos.len() == 1 {
+        let (post, photo) = photos.pop().unwrap();
+        let msg = send_upload(bot, chat, post, Upload::Photo(photo)).await?;
+        files.learn(post, &msg);
+    } else if !photos.is_empty() {
+        let sent = photos.iter().map(|(post, _)| *post).collect::<Vec<_>>();
+        let media = photos.into_iter().map(|(post, photo)| {
+            let file = InputFile::memory(photo).file_name(format!("{}.jpg", post.id()));
+            InputMedia::Photo(
+                InputMediaPhoto::new(file)
+                    .caption(caption(post))
+                    .parse_mode(ParseMode::Html),
+            )
+        });
+        let messages = bot.send_media_group(chat, media).await?;
+        for (post, msg) in sent.into_iter().zip(messages.iter()) {
+            files.learn(post, msg);
+        }
+    }
+    for (post, document) in documents {
+        send_upload(bot, chat, post, document).await?;
+    }
+    Ok(())
+}
+
+fn media_file(post: &Post) -> InputFile {
+    InputFile::url(Url::parse(post.media_href.as_str()).unwrap())
+}
+
+fn caption(post: &Post) -> String {
+    format!("<i>{}</i>", post.title())
+}
+
+#[test]
+fn test_token_bucket() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(CHAT_BURST, CHAT_RATE);
+    for _ in 0..3 {
+        assert!(bucket.wait_for(1.0, start).is_zero());
+        bucket.take(1.0, start);
+    }
+    assert_eq!(bucket.wait_for(1.0, start), Duration::from_secs(1));
+    assert!(bucket
+        .wait_for(1.0, start + Duration::from_secs(1))
+        .is_zero());
+
+    // An album bigger than the burst waits for a full bucket then runs a debt.
+    let mut bucket = TokenBucket::new(CHAT_BURST, CHAT_RATE);
+    bucket.take(10.0, start);
+    assert_eq!(bucket.wait_for(1.0, start), Duration::from_secs(8));
+
+    bucket.pause(Duration::from_secs(30), start);
+    assert!(bucket.wait_for(1.0, start) >= Duration::from_secs(30));
+}
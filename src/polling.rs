@@ -0,0 +1,157 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde::Deserialize;
+
+pub const POLL_INTERVAL_MIN: u64 = 5;
+
+pub const POLL_INTERVAL_MAX: u64 = 15 * 60;
+
+// Weight of the latest gap between posts in the posting rate estimate.
+const EWMA_WEIGHT: f64 = 0.3;
+
+// Listings are polled this many times per expected gap between posts, so a
+// new post waits about half a gap on average before it's picked up.
+const POLLS_PER_GAP: f64 = 2.0;
+
+// Share of the interval each poll is moved by at random, so listings that
+// were started together don't keep hitting the API at the same moment.
+const JITTER: f64 = 0.1;
+
+/// The seconds a learnt polling interval is kept between.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PollLimits {
+    pub min_interval: u64,
+    pub max_interval: u64,
+}
+
+impl Default for PollLimits {
+    fn default() -> Self {
+        Self {
+            min_interval: POLL_INTERVAL_MIN,
+            max_interval: POLL_INTERVAL_MAX,
+        }
+    }
+}
+
+/// Learns how often a listing gets new posts and how long to wait between
+/// polls because of it.
+#[derive(Debug, Clone)]
+pub struct PollSchedule {
+    /// Moving average of the seconds between two posts.
+    mean_gap: Option<f64>,
+    last_posted: Option<DateTime<Utc>>,
+    interval: Duration,
+    limits: PollLimits,
+}
+
+impl Default for PollSchedule {
+    fn default() -> Self {
+        Self::new(PollLimits::default())
+    }
+}
+
+impl PollSchedule {
+    pub fn new(limits: PollLimits) -> Self {
+        Self {
+            mean_gap: None,
+            last_posted: None,
+            interval: Duration::from_secs(limits.min_interval),
+            limits,
+        }
+    }
+
+    /// A schedule that isn't learnt, e.g. for random picks.
+    pub fn fixed(interval: Duration) -> Self {
+        Self {
+            interval,
+            ..Default::default()
+        }
+    }
+
+    /// Records the new posts of a poll by when they were submitted, which
+    /// may be empty, and works out the next interval. `floor` is the fair
+    /// share of the API budget this listing may use.
+    pub fn observe(&mut self, posted: &[DateTime<Utc>], now: DateTime<Utc>, floor: Duration) {
+        let mut posted = posted.to_vec();
+        posted.sort();
+        for at in posted {
+            if let Some(last) = self.last_posted {
+                let gap = (at - last).num_milliseconds().max(0) as f64 / 1000.0;
+                self.mean_gap = Some(match self.mean_gap {
+                    Some(mean) => EWMA_WEIGHT * gap + (1.0 - EWMA_WEIGHT) * mean,
+                    None => gap,
+                });
+            }
+            self.last_posted = Some(self.last_posted.map_or(at, |last| last.max(at)));
+        }
+
+        // A listing quiet for longer than usual is slower than estimated.
+        let silence = self
+            .last_posted
+            .map(|last| (now - last).num_milliseconds().max(0) as f64 / 1000.0);
+        let expected_gap = match (self.mean_gap, silence) {
+            (Some(mean), Some(silence)) => mean.max(silence),
+            (None, Some(silence)) => silence,
+            (mean, None) => mean.unwrap_or(0.0),
+        };
+
+        let secs = (expected_gap / POLLS_PER_GAP)
+            .max(self.limits.min_interval as f64)
+            .max(floor.as_secs_f64())
+            .min(self.limits.max_interval as f64);
+        self.interval = Duration::from_secs_f64(secs);
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn mean_gap(&self) -> Option<Duration> {
+        self.mean_gap.map(Duration::from_secs_f64)
+    }
+
+    /// The interval moved by up to `JITTER` either way.
+    pub fn jittered(&self) -> Duration {
+        let factor = rand::thread_rng().gen_range(1.0 - JITTER..=1.0 + JITTER);
+        self.interval.mul_f64(factor)
+    }
+}
+
+#[test]
+fn test_poll_schedule() {
+    let start = Utc::now();
+    let at = |secs: i64| start + chrono::Duration::seconds(secs);
+    let mut schedule = PollSchedule::default();
+
+    // A post every minute is polled every half minute.
+    schedule.observe(&[at(0), at(60), at(120)], at(120), Duration::ZERO);
+    assert_eq!(schedule.mean_gap(), Some(Duration::from_secs(60)));
+    assert_eq!(schedule.interval(), Duration::from_secs(30));
+
+    // Going quiet stretches the interval, up to the max.
+    schedule.observe(&[], at(720), Duration::ZERO);
+    assert_eq!(schedule.interval(), Duration::from_secs(300));
+    schedule.observe(&[], at(86400), Duration::ZERO);
+    assert_eq!(schedule.interval(), Duration::from_secs(POLL_INTERVAL_MAX));
+
+    // Busy listings are polled often, but never below the budget's floor.
+    let mut schedule = PollSchedule::default();
+    schedule.observe(&[at(0), at(1), at(2)], at(2), Duration::from_secs(20));
+    assert_eq!(schedule.interval(), Duration::from_secs(20));
+
+    let jittered = schedule.jittered();
+    assert!(jittered >= Duration::from_secs(18) && jittered <= Duration::from_secs(22));
+
+    // Configured limits replace the defaults.
+    let mut schedule = PollSchedule::new(PollLimits {
+        min_interval: 60,
+        max_interval: 120,
+    });
+    schedule.observe(&[at(0), at(1), at(2)], at(2), Duration::ZERO);
+    assert_eq!(schedule.interval(), Duration::from_secs(60));
+    schedule.observe(&[], at(86400), Duration::ZERO);
+    assert_eq!(schedule.interval(), Duration::from_secs(120));
+}
@@ -0,0 +1,417 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use futures::future::join_all;
+use log::{error, info, warn};
+use teloxide::types::ChatId;
+use teloxide::{ApiError, RequestError};
+use tokio::time::{sleep_until, Instant};
+
+use crate::auth::ClientID;
+use crate::content::Post;
+use crate::db::{self, with_conn, DbPool, UtcTime};
+use crate::outbound::Outbox;
+use crate::schema::{artposts, dead_letters, outbound_posts};
+
+pub const RETRY_CHECK_INTERVAL: u64 = 10;
+
+pub const DELIVERY_ATTEMPTS_MAX: i32 = 8;
+
+// Backoff between attempts doubles from the base up to the max.
+const RETRY_BACKOFF_BASE: i64 = 30;
+const RETRY_BACKOFF_MAX: i64 = 6 * 60 * 60;
+
+// How long a post being sent is left alone by the `RetryWorker`.
+const SEND_LEASE: i64 = 120;
+
+/// How a failed send should be followed up.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Failure {
+    /// Worth retrying later, e.g. network errors or flood limits.
+    Transient,
+    /// Telegram couldn't fetch or use the post's media, which `Outbox` then
+    /// tries to upload itself.
+    MediaRejected,
+    /// Retrying can't help, e.g. the bot was blocked or the chat is gone.
+    Terminal,
+}
+
+impl Failure {
+    pub fn classify(e: &RequestError) -> Failure {
+        match e {
+            RequestError::Api(api) => match api {
+                ApiError::WrongFileId
+                | ApiError::WrongFileIdOrUrl
+                | ApiError::FailedToGetUrlContent
+                | ApiError::ImageProcessFailed
+                | ApiError::PhotoAsInputFileRequired
+                | ApiError::WrongHttpUrl
+                | ApiError::FileIdInvalid
+                | ApiError::RequestEntityTooLarge => Failure::MediaRejected,
+                ApiError::BotBlocked
+                | ApiError::BotKicked
+                | ApiError::BotKickedFromSupergroup
+                | ApiError::ChatNotFound
+                | ApiError::UserNotFound
+                | ApiError::UserDeactivated
+                | ApiError::GroupDeactivated
+                | ApiError::CantInitiateConversation
+                | ApiError::CantTalkWithBots
+                | ApiError::NotEnoughRightsToPostMessages
+                | ApiError::CantParseEntities => Failure::Terminal,
+                ApiError::Unknown(description) => {
+                    let description = description.to_lowercase();
+                    if description.contains("wrong type of the web page content")
+                        || description.contains("failed to get http url content")
+                        || description.contains("wrong file identifier")
+                    {
+                        Failure::MediaRejected
+                    } else {
+                        Failure::Transient
+                    }
+                }
+                _ => Failure::Transient,
+            },
+            RequestError::MigrateToChatId(_) => Failure::Terminal,
+            _ => Failure::Transient,
+        }
+    }
+}
+
+/// Delay before the next attempt once `attempts` have failed.
+pub fn backoff(attempts: i32) -> chrono::Duration {
+    let exp = attempts.clamp(1, 16) as u32 - 1;
+    chrono::Duration::seconds((RETRY_BACKOFF_BASE << exp).min(RETRY_BACKOFF_MAX))
+}
+
+/// A delivery due for another attempt.
+#[derive(Queryable, Debug)]
+pub struct OutboundPost {
+    pub chat_id: i64,
+    pub post_id: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+}
+
+/// Why a post was given up on, as shown by `/failed`.
+#[derive(Queryable, Debug)]
+pub struct DeadLetter {
+    pub attempts: i32,
+    pub error: String,
+    pub failed_at: DateTime<Utc>,
+}
+
+/// Posts waiting to be sent to a chat, kept until Telegram accepts them or
+/// they're moved to the dead letters.
+pub struct DeliveryQueue {
+    db: DbPool,
+}
+
+impl DeliveryQueue {
+    pub fn from(pool: &DbPool) -> Self {
+        Self { db: pool.clone() }
+    }
+
+    /// Queues a post for delivery. With `leased`, the caller sends it right
+    /// away and the `RetryWorker` only picks it up if that goes wrong. The
+    /// post must already be stored in the `ArtVault`.
+    pub fn push(&mut self, chat: ClientID, post: &Post, leased: bool) {
+        let at = if leased {
+            Utc::now() + chrono::Duration::seconds(SEND_LEASE)
+        } else {
+            Utc::now()
+        };
+        self.schedule(chat, post, at);
+    }
+
+    /// Queues a post for the `RetryWorker` to send once `at` has passed.
+    pub fn schedule(&mut self, chat: ClientID, post: &Post, at: DateTime<Utc>) {
+        use crate::schema::outbound_posts::dsl::*;
+
+        let res = with_conn!(self.db, |conn| diesel::insert_into(outbound_posts)
+            .values((
+                chat_id.eq(chat.id()),
+                post_id.eq(post.id()),
+                next_attempt_at.eq(UtcTime(at)),
+            ))
+            .on_conflict_do_nothing()
+            .execute(conn));
+        if let Err(e) = res {
+            error!(
+                "couldn't queue PostID \"{}\" for delivery: {}",
+                post.id(),
+                e
+            );
+        }
+    }
+
+    /// The posts queued for `chat` and not delivered yet.
+    pub fn pending(&mut self, chat: ClientID) -> Vec<String> {
+        use crate::schema::outbound_posts::dsl::*;
+
+        with_conn!(self.db, |conn| outbound_posts
+            .filter(chat_id.eq(chat.id()))
+            .select(post_id)
+            .load::<String>(conn))
+        .unwrap_or_else(|e| {
+            error!("error loading pending deliveries: {}", e);
+            vec![]
+        })
+    }
+
+    /// When the post queued last for `chat` is due, if any is queued.
+    pub fn last_scheduled(&mut self, chat: ClientID) -> Option<DateTime<Utc>> {
+        use crate::schema::outbound_posts::dsl::*;
+
+        with_conn!(self.db, |conn| outbound_posts
+            .filter(chat_id.eq(chat.id()))
+            .select(next_attempt_at)
+            .order(next_attempt_at.desc())
+            .first::<DateTime<Utc>>(conn)
+            .optional())
+        .unwrap_or_else(|e| {
+            error!("error loading scheduled deliveries: {}", e);
+            None
+        })
+    }
+
+    /// Leases up to `limit` posts whose next attempt is due.
+    fn due(&mut self, now: DateTime<Utc>, limit: i64) -> Vec<(OutboundPost, Post)> {
+        use crate::schema::outbound_posts::dsl::*;
+
+        let due = with_conn!(self.db, |conn| outbound_posts
+            .inner_join(artposts::table)
+            .filter(next_attempt_at.le(UtcTime(now)))
+            .order(next_attempt_at.asc())
+            .limit(limit)
+            .select((
+                (chat_id, post_id, attempts, last_error),
+                artposts::all_columns,
+            ))
+            .load::<(OutboundPost, Post)>(conn))
+        .unwrap_or_else(|e| {
+            error!("error loading due deliveries: {}", e);
+            vec![]
+        });
+
+        for (outbound, _) in due.iter() {
+            let res = with_conn!(self.db, |conn| diesel::update(
+                outbound_posts.find((outbound.chat_id, &outbound.post_id))
+            )
+            .set(next_attempt_at.eq(UtcTime(now + chrono::Duration::seconds(SEND_LEASE))))
+            .execute(conn));
+            if let Err(e) = res {
+                error!("couldn't lease PostID \"{}\": {}", outbound.post_id, e);
+            }
+        }
+        due
+    }
+
+    /// Records the outcome of an attempt at sending `post`, which had failed
+    /// `failed_attempts` times before.
+    pub fn settle(
+        &mut self,
+        chat: ClientID,
+        post: &Post,
+        failed_attempts: i32,
+        res: &Result<(), RequestError>,
+        now: DateTime<Utc>,
+    ) {
+        let e = match res {
+            Ok(_) => {
+                let res = with_conn!(self.db, |conn| diesel::delete(
+                    outbound_posts::table.find((chat.id(), post.id()))
+                )
+                .execute(conn));
+                if let Err(e) = res {
+                    error!("couldn't dequeue delivered PostID \"{}\": {}", post.id(), e);
+                }
+                return;
+            }
+            Err(e) => e,
+        };
+
+        let attempts = failed_attempts + 1;
+        let failure = Failure::classify(e);
+        if failure != Failure::Transient || attempts >= DELIVERY_ATTEMPTS_MAX {
+            self.bury(chat, post, attempts, e.to_string(), now);
+            return;
+        }
+
+        let retry_at = now + backoff(attempts);
+        warn!(
+            "Delivery of PostID: '{}' to ChatID: '{}' failed {} time(s), retrying at {}: {}",
+            post.id(),
+            chat.id(),
+            attempts,
+            retry_at,
+            e
+        );
+        let res = with_conn!(self.db, |conn| diesel::update(
+            outbound_posts::table.find((chat.id(), post.id()))
+        )
+        .set((
+            outbound_posts::attempts.eq(attempts),
+            outbound_posts::next_attempt_at.eq(UtcTime(retry_at)),
+            outbound_posts::last_error.eq(Some(e.to_string())),
+        ))
+        .execute(conn));
+        if let Err(e) = res {
+            error!("couldn't reschedule PostID \"{}\": {}", post.id(), e);
+        }
+    }
+
+    /// Moves a post that can't be delivered to the dead letters.
+    fn bury(
+        &mut self,
+        chat: ClientID,
+        post: &Post,
+        attempts: i32,
+        reason: String,
+        now: DateTime<Utc>,
+    ) {
+        error!(
+            "Giving up on PostID: '{}' for ChatID: '{}' after {} attempt(s): {}",
+            post.id(),
+            chat.id(),
+            attempts,
+            reason
+        );
+        let res = with_conn!(self.db, |db| db.transaction(|conn| {
+            diesel::delete(outbound_posts::table.find((chat.id(), post.id()))).execute(conn)?;
+            diesel::insert_into(dead_letters::table)
+                .values((
+                    dead_letters::chat_id.eq(chat.id()),
+                    dead_letters::post_id.eq(post.id()),
+                    dead_letters::attempts.eq(attempts),
+                    dead_letters::error.eq(&reason),
+                    dead_letters::failed_at.eq(UtcTime(now)),
+                ))
+                .on_conflict((dead_letters::chat_id, dead_letters::post_id))
+                .do_update()
+                .set((
+                    dead_letters::attempts.eq(attempts),
+                    dead_letters::error.eq(&reason),
+                    dead_letters::failed_at.eq(UtcTime(now)),
+                ))
+                .execute(conn)
+        }));
+        if let Err(e) = res {
+            error!(
+                "couldn't record dead letter for PostID \"{}\": {}",
+                post.id(),
+                e
+            );
+        }
+    }
+
+    /// The most recent posts that couldn't be delivered to `chat`.
+    pub fn dead_letters(&mut self, chat: ClientID, limit: i64) -> Vec<(DeadLetter, Post)> {
+        use crate::schema::dead_letters::dsl::*;
+
+        with_conn!(self.db, |conn| dead_letters
+            .inner_join(artposts::table)
+            .filter(chat_id.eq(chat.id()))
+            .order(failed_at.desc())
+            .limit(limit)
+            .select(((attempts, error, failed_at), artposts::all_columns))
+            .load::<(DeadLetter, Post)>(conn))
+        .unwrap_or_else(|e| {
+            error!("error loading dead letters: {}", e);
+            vec![]
+        })
+    }
+}
+
+/// Retries deliveries that failed or were interrupted, e.g. by a restart.
+pub struct RetryWorker {
+    outbox: Outbox,
+    db: DbPool,
+}
+
+impl RetryWorker {
+    pub fn from(outbox: Outbox, pool: &DbPool) -> Self {
+        Self {
+            outbox,
+            db: pool.clone(),
+        }
+    }
+
+    pub async fn run(mut self) {
+        loop {
+            self.retry_due(Utc::now()).await;
+            sleep_until(Instant::now() + Duration::from_secs(RETRY_CHECK_INTERVAL)).await;
+        }
+    }
+
+    async fn retry_due(&mut self, now: DateTime<Utc>) {
+        let pool = self.db.clone();
+        let due = db::blocking(move || {
+            DeliveryQueue::from(&pool).due(now, crate::outbound::OUTBOX_CAPACITY as i64)
+        })
+        .await;
+        if due.is_empty() {
+            return;
+        }
+        info!("Retrying {} pending deliveries", due.len());
+
+        for (outbound, post) in due.iter() {
+            if let Some(e) = &outbound.last_error {
+                info!(
+                    "Retrying PostID: '{}' for ChatID: '{}', last failed with: {}",
+                    post.id(),
+                    outbound.chat_id,
+                    e
+                );
+            }
+        }
+        let outbox = &self.outbox;
+        let sends = due.iter().map(|(outbound, post)| async move {
+            outbox.send_post(ChatId(outbound.chat_id), post).await
+        });
+        let results = join_all(sends).await;
+
+        let pool = self.db.clone();
+        db::blocking(move || {
+            let mut queue = DeliveryQueue::from(&pool);
+            for ((outbound, post), res) in due.iter().zip(results) {
+                queue.settle(
+                    outbound.chat_id.into(),
+                    post,
+                    outbound.attempts,
+                    &res,
+                    Utc::now(),
+                );
+            }
+        })
+        .await;
+    }
+}
+
+#[test]
+fn test_delivery_failures() {
+    use std::time::Duration;
+
+    assert_eq!(
+        Failure::classify(&RequestError::RetryAfter(Duration::from_secs(3))),
+        Failure::Transient
+    );
+    assert_eq!(
+        Failure::classify(&RequestError::Api(ApiError::BotBlocked)),
+        Failure::Terminal
+    );
+    assert_eq!(
+        Failure::classify(&RequestError::Api(ApiError::Unknown(
+            "Bad Request: wrong type of the web page content".to_string()
+        ))),
+        Failure::MediaRejected
+    );
+
+    assert_eq!(backoff(1), chrono::Duration::seconds(30));
+    assert_eq!(backoff(3), chrono::Duration::seconds(120));
+    assert_eq!(
+        backoff(DELIVERY_ATTEMPTS_MAX * 2),
+        chrono::Duration::hours(6)
+    );
+}
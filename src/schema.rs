@@ -1,6 +1,9 @@
 // @generated automatically by Diesel CLI.
 
 diesel::table! {
+    use diesel::sql_types::*;
+    use crate::db::sql_types::Timestamptz;
+
     artposts (id) {
         id -> Text,
         media_href -> Text,
@@ -8,6 +11,7 @@ diesel::table! {
         author -> Text,
         ups -> Int4,
         downs -> Int4,
+        posted_at -> Nullable<Timestamptz>,
     }
 }
 
@@ -20,19 +24,125 @@ diesel::table! {
 }
 
 diesel::table! {
+    chat_settings (chat_id) {
+        chat_id -> Int8,
+        timezone -> Text,
+        digest_hour -> Int2,
+        quiet_start -> Nullable<Int2>,
+        quiet_end -> Nullable<Int2>,
+        hourly_budget -> Nullable<Int4>,
+        overflow_policy -> Text,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use crate::db::sql_types::Timestamptz;
+
+    dead_letters (chat_id, post_id) {
+        chat_id -> Int8,
+        post_id -> Text,
+        attempts -> Int4,
+        error -> Text,
+        failed_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use crate::db::sql_types::Timestamptz;
+
+    listing_cursors (source, subreddit, category) {
+        source -> Text,
+        subreddit -> Text,
+        category -> Text,
+        anchor -> Text,
+        updated_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use crate::db::sql_types::Timestamptz;
+
+    media_files (media_href) {
+        media_href -> Text,
+        file_id -> Text,
+        cached_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use crate::db::sql_types::Timestamptz;
+
+    outbound_posts (chat_id, post_id) {
+        chat_id -> Int8,
+        post_id -> Text,
+        attempts -> Int4,
+        next_attempt_at -> Timestamptz,
+        last_error -> Nullable<Text>,
+        queued_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use crate::db::sql_types::Timestamptz;
+
+    queued_posts (chat_id, post_id) {
+        chat_id -> Int8,
+        subreddit -> Text,
+        category -> Text,
+        post_id -> Text,
+        score -> Int4,
+        queued_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use crate::db::sql_types::Timestamptz;
+
+    seen_posts (source, subreddit, category, post_id) {
+        source -> Text,
+        subreddit -> Text,
+        category -> Text,
+        post_id -> Text,
+        seen_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use crate::db::sql_types::Timestamptz;
+
     subscribed_listings (user_id, subreddit, category) {
         user_id -> Int8,
         subreddit -> Text,
         category -> Text,
         head_post_id -> Nullable<Text>,
+        delivery_mode -> Text,
+        digest_size -> Nullable<Int4>,
+        last_digest_at -> Nullable<Timestamptz>,
     }
 }
 
+diesel::joinable!(dead_letters -> artposts (post_id));
+diesel::joinable!(outbound_posts -> artposts (post_id));
+diesel::joinable!(queued_posts -> artposts (post_id));
 diesel::joinable!(subscribed_listings -> artposts (head_post_id));
 diesel::joinable!(subscribed_listings -> botclients (user_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
     artposts,
     botclients,
+    chat_settings,
+    dead_letters,
+    listing_cursors,
+    media_files,
+    outbound_posts,
+    queued_posts,
+    seen_posts,
     subscribed_listings,
 );
@@ -0,0 +1,157 @@
+use std::collections::{HashSet, VecDeque};
+
+use chrono::Utc;
+use diesel::prelude::*;
+use log::error;
+
+use crate::content::Post;
+use crate::curator::FeedKey;
+use crate::db::{with_conn, DbPool, UtcTime};
+
+// Posts remembered per listing; comfortably more than a busy listing gets
+// between two polls, even after a long outage.
+pub const SEEN_CAPACITY: usize = 1000;
+
+/// The most recent posts a listing's feed already broadcast, bounded to
+/// `capacity` and forgetting the oldest first.
+pub struct SeenSet {
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+    capacity: usize,
+}
+
+impl SeenSet {
+    pub fn new(capacity: usize) -> Self {
+        if capacity == 0 {
+            panic!("seen-set must be of size > 0");
+        }
+        Self {
+            ids: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn contains(&self, post_id: &str) -> bool {
+        self.ids.contains(post_id)
+    }
+
+    /// Remembers a post, returning the one forgotten to make room for it.
+    pub fn insert(&mut self, post_id: String) -> Option<String> {
+        if !self.ids.insert(post_id.clone()) {
+            return None;
+        }
+        self.order.push_back(post_id);
+        if self.order.len() <= self.capacity {
+            return None;
+        }
+        let oldest = self.order.pop_front()?;
+        self.ids.remove(&oldest);
+        Some(oldest)
+    }
+
+    /// The posts that weren't seen yet, in order and without duplicates.
+    pub fn unseen(&self, posts: VecDeque<Post>) -> Vec<Post> {
+        let mut batch = HashSet::new();
+        posts
+            .into_iter()
+            .filter(|post| !self.contains(post.id()) && batch.insert(post.id().clone()))
+            .collect()
+    }
+}
+
+/// Keeps each feed's `SeenSet` in the database, so a restart doesn't send
+/// the latest posts of every listing again.
+pub struct SeenStore {
+    db: DbPool,
+}
+
+impl SeenStore {
+    pub fn from(pool: &DbPool) -> Self {
+        Self { db: pool.clone() }
+    }
+
+    /// The feed's seen-set as it was last saved.
+    pub fn load(&mut self, key: &FeedKey, capacity: usize) -> SeenSet {
+        use crate::schema::seen_posts::dsl::*;
+
+        let mut ids = with_conn!(self.db, |conn| seen_posts
+            .filter(source.eq(key.source))
+            .filter(subreddit.eq(key.target.name()))
+            .filter(category.eq(&key.category))
+            .order(seen_at.desc())
+            .limit(capacity as i64)
+            .select(post_id)
+            .load::<String>(conn))
+        .unwrap_or_else(|e| {
+            error!("error loading seen posts of {}: {}", key, e);
+            vec![]
+        });
+        ids.reverse();
+
+        let mut seen = SeenSet::new(capacity);
+        for id in ids {
+            seen.insert(id);
+        }
+        seen
+    }
+
+    /// Saves what the feed saw since the last call and what it forgot.
+    pub fn record(&mut self, key: &FeedKey, seen: &[String], forgotten: &[String]) {
+        use crate::schema::seen_posts::dsl::*;
+
+        let now = UtcTime(Utc::now());
+        let rows = seen
+            .iter()
+            .map(|id| {
+                (
+                    source.eq(key.source),
+                    subreddit.eq(key.target.name()),
+                    category.eq(&key.category),
+                    post_id.eq(id),
+                    seen_at.eq(now),
+                )
+            })
+            .collect::<Vec<_>>();
+        let res = with_conn!(self.db, |db| db.transaction(|conn| {
+            diesel::insert_into(seen_posts)
+                .values(&rows)
+                .on_conflict_do_nothing()
+                .execute(conn)?;
+            diesel::delete(
+                seen_posts
+                    .filter(source.eq(key.source))
+                    .filter(subreddit.eq(key.target.name()))
+                    .filter(category.eq(&key.category))
+                    .filter(post_id.eq_any(forgotten)),
+            )
+            .execute(conn)
+        }));
+        if let Err(e) = res {
+            error!("couldn't save seen posts of {}: {}", key, e);
+        }
+    }
+}
+
+#[test]
+fn test_seen_set() {
+    let post = |id: &str| {
+        Post::new(
+            id.to_string(),
+            String::new(),
+            String::new(),
+            String::new(),
+            (0, 0),
+        )
+    };
+    let mut seen = SeenSet::new(2);
+    assert_eq!(seen.insert("a".to_string()), None);
+    assert_eq!(seen.insert("a".to_string()), None);
+    assert_eq!(seen.insert("b".to_string()), None);
+    assert_eq!(seen.insert("c".to_string()), Some("a".to_string()));
+    assert_eq!(seen.order.len(), 2);
+    assert!(!seen.contains("a"));
+
+    let unseen = seen.unseen(VecDeque::from(vec![post("a"), post("b"), post("a")]));
+    assert_eq!(unseen, vec![post("a")]);
+}
@@ -0,0 +1,120 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{error, info};
+use tokio::spawn;
+use tokio::time::{sleep_until, Instant};
+use tokio_util::sync::CancellationToken;
+
+// Backoff between restarts doubles from the base up to the max.
+const RESTART_BACKOFF_BASE: u64 = 1;
+const RESTART_BACKOFF_MAX: u64 = 5 * 60;
+
+// A task that ran this long before failing starts over from the base backoff.
+const HEALTHY_RUN: u64 = 10 * 60;
+
+/// What a supervised task is up to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Health {
+    Running,
+    /// Crashed and waits to be started again.
+    Restarting {
+        error: String,
+    },
+    /// Finished on its own or was cancelled, and won't be restarted.
+    Stopped,
+}
+
+#[derive(Debug, Clone)]
+pub struct TaskHealth {
+    pub health: Health,
+    pub restarts: u32,
+    /// Why the task last crashed, if it ever did.
+    pub last_error: Option<String>,
+}
+
+impl Default for TaskHealth {
+    fn default() -> Self {
+        Self {
+            health: Health::Running,
+            restarts: 0,
+            last_error: None,
+        }
+    }
+}
+
+/// Delay before restarting a task that failed `failures` times in a row.
+pub fn restart_backoff(failures: u32) -> Duration {
+    let exp = failures.clamp(1, 16) - 1;
+    Duration::from_secs((RESTART_BACKOFF_BASE << exp).min(RESTART_BACKOFF_MAX))
+}
+
+/// Runs the task built by `start` until it finishes on its own or `cancel`
+/// fires, starting it again with backoff whenever it panics. Tasks are
+/// expected to watch `cancel` themselves, so they get to clean up.
+pub async fn supervise<F, Fut>(
+    name: String,
+    cancel: CancellationToken,
+    health: Arc<std::sync::Mutex<TaskHealth>>,
+    mut start: F,
+) where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let mut failures = 0;
+    loop {
+        health.lock().unwrap().health = Health::Running;
+        let started = Instant::now();
+        let res = spawn(start()).await;
+
+        let e = match res {
+            Ok(()) => break,
+            Err(e) if e.is_cancelled() => break,
+            Err(e) => e,
+        };
+        let reason = match e.into_panic().downcast::<String>() {
+            Ok(msg) => *msg,
+            Err(payload) => match payload.downcast::<&str>() {
+                Ok(msg) => msg.to_string(),
+                Err(_) => "unknown panic".to_string(),
+            },
+        };
+        if started.elapsed() >= Duration::from_secs(HEALTHY_RUN) {
+            failures = 0;
+        }
+        failures += 1;
+        let wait = restart_backoff(failures);
+        error!(
+            "{} crashed: {}, restarting in {}s",
+            name,
+            reason,
+            wait.as_secs()
+        );
+        {
+            let mut health = health.lock().unwrap();
+            health.health = Health::Restarting {
+                error: reason.clone(),
+            };
+            health.restarts += 1;
+            health.last_error = Some(reason);
+        }
+
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            _ = sleep_until(Instant::now() + wait) => (),
+        }
+    }
+    info!("{} stopped", name);
+    health.lock().unwrap().health = Health::Stopped;
+}
+
+#[test]
+fn test_restart_backoff() {
+    assert_eq!(restart_backoff(1), Duration::from_secs(1));
+    assert_eq!(restart_backoff(4), Duration::from_secs(8));
+    assert_eq!(
+        restart_backoff(100),
+        Duration::from_secs(RESTART_BACKOFF_MAX)
+    );
+}
@@ -1,22 +1,34 @@
 use std::sync::Arc;
 
-use log::{info, warn};
-use reqwest::{Client, Url};
+use chrono::Utc;
+use chrono_tz::Tz;
+use diesel::QueryResult;
+use log::{error, info, warn};
 use teloxide::macros::BotCommands;
-use teloxide::payloads::SendPhotoSetters;
 use teloxide::prelude::*;
 use teloxide::prelude::{Message, Requester, ResponseResult};
-use teloxide::types::{InputFile, Me, ParseMode};
+use teloxide::types::Me;
 use teloxide::Bot;
 use tokio::spawn;
 use tokio::sync::Mutex;
 
-use crate::aggregator::AggregatorStore;
-use crate::artvault::ArtVault;
-use crate::auth::{BotClient, ClientID, ClientManager};
-use crate::curator::Curator;
-use crate::listings::reddit::{Api, Listing, Subreddit};
-use crate::telegram::Command::{Listen, Silence};
+use crate::aggregator::{Listeners, UserAggregator};
+use crate::auth::{BotClient, ClientID};
+use crate::backfill::{self, BackfillLimit};
+use crate::config::Config;
+use crate::curator::{Curator, FeedEvent};
+use crate::db::{self, DbPool, Storage};
+use crate::delivery::{
+    DeliveryMode, DeliveryThrottle, DigestQueue, DigestSchedule, OverflowPolicy,
+};
+use crate::listings::reddit::{Listing, Subreddit};
+use crate::listings::source::ListingSource;
+use crate::outbound::Outbox;
+use crate::retry::DeliveryQueue;
+use crate::supervisor::Health;
+use crate::telegram::Command::{
+    Backfill, Budget, Deliver, Digest, Failed, Listen, Polling, Quiet, Random, Silence,
+};
 
 #[derive(BotCommands, Clone)]
 #[command(rename_rule = "lowercase", description = "ConfCommand")]
@@ -31,28 +43,63 @@ pub enum ConfCommand {
     Settings,
 }
 
+/// Routes commands to `listen_silence_handler`, which parses their arguments
+/// itself.
 #[derive(BotCommands, Clone)]
 #[command(rename_rule = "lowercase", description = "SubscribeCommand")]
 pub enum SubscribeCommand {
-    #[command(parse_with = "split", description = "generate a number within range")]
-    Listen {
-        subname: String,
-        category: String,
-    },
-    Silence {
-        subname: String,
-    },
+    #[command(
+        description = "listen to the new, hot, rising, top or controversial posts of a subreddit, r/a+b, u/user, m/owner/multi or search:query"
+    )]
+    Listen,
+    #[command(description = "stop listening to a subreddit")]
+    Silence,
+    #[command(
+        description = "deliver a listing in real-time, hourly, daily, weekly or as its top N posts"
+    )]
+    Deliver,
+    #[command(description = "set the local hour & timezone digests are sent at")]
+    Digest,
+    #[command(description = "set hours during which nothing is delivered")]
+    Quiet,
+    #[command(description = "limit how many posts are delivered per hour")]
+    Budget,
+    #[command(description = "list posts that couldn't be delivered")]
+    Failed,
+    #[command(description = "send a random post from a subreddit")]
+    Random,
+    #[command(
+        description = "deliver a listing's last N posts, or its new posts since a YYYY-MM-DD date"
+    )]
+    Backfill,
+    #[command(description = "show how often each listing is polled and how its feed is doing")]
+    Polling,
 }
 
 pub async fn configuration_cmd_handler(
     tg_bot: Bot,
     msg: Message,
     cmd: ConfCommand,
+    storage: Arc<dyn Storage>,
 ) -> Result<(), teloxide::RequestError> {
     match cmd {
         ConfCommand::Start => {
-            let mut cli_mgr = ClientManager::instance();
-            if let Some(registered) = cli_mgr.get(ClientID::from(msg.from().unwrap().id.0 as i64)) {
+            let user = msg.from().unwrap();
+            let botclient = BotClient {
+                id: ClientID::from(user.id.0 as i64),
+                username: user.username.clone(),
+                is_user: !user.is_bot,
+            };
+            let registered = db::blocking(move || {
+                let mut cli_mgr = storage.clients();
+                let registered = cli_mgr.get(botclient.id).cloned();
+                if registered.is_none() {
+                    cli_mgr.add(botclient);
+                }
+                registered
+            })
+            .await;
+            if let Some(registered) = registered {
                 tg_bot
                     .send_message(
                         ChatId(registered.id.id()),
@@ -60,13 +107,6 @@ pub async fn configuration_cmd_handler(
                     )
                     .await
                     .unwrap();
-            } else {
-                let botclient = BotClient {
-                    id: ClientID::from(msg.from().unwrap().id.0 as i64),
-                    username: msg.from().unwrap().username.clone(),
-                    is_user: !msg.from().unwrap().is_bot,
-                };
-                cli_mgr.add(botclient);
             }
             Ok(())
         }
@@ -75,11 +115,172 @@ pub async fn configuration_cmd_handler(
     }
 }
 
-pub async fn listen_silence_handler(
+/// Forwards the posts of `listing` to `chat` until it's silenced or the
+/// listing goes away. Returns `false` if the chat already listens to it.
+#[allow(clippy::too_many_arguments)]
+pub fn listen<T: ListingSource>(
+    chat: ChatId,
+    listing: Listing,
+    storage: Arc<dyn Storage>,
+    throttle: Arc<Mutex<DeliveryThrottle>>,
+    outbox: Outbox,
+    curator: &Curator<T>,
+    listeners: &Listeners,
+    pool: DbPool,
+) -> bool {
+    let client = ClientID::from(chat.0);
+    let cancel = match listeners.start(client, &listing) {
+        Some(cancel) => cancel,
+        None => {
+            info!(
+                "ChatID: '{}' already listens to {}/{}",
+                client.id(),
+                listing.subreddit(),
+                listing.category()
+            );
+            return false;
+        }
+    };
+    let mut user = UserAggregator::create(curator);
+
+    let (listeners, subscribed) = (listeners.clone(), listing.clone());
+    let task = async move {
+        user.add_listing(listing);
+
+        // Ends once silenced, dropping the subscription along with
+        // `user`, which stops the feed if no other chat listens.
+        while let Some(event) = tokio::select! {
+            _ = cancel.cancelled() => None,
+            event = user.recv() => event,
+        } {
+            let post = match event {
+                FeedEvent::Post(post) => post,
+                FeedEvent::Gone(reason) => {
+                    listeners.finish(client, &subscribed);
+                    let (db_storage, listing) = (storage.clone(), subscribed.clone());
+                    let res = db::blocking(move || {
+                        db_storage.subscriptions().unsubscribe(client, &listing)
+                    })
+                    .await;
+                    if let Err(e) = res {
+                        error!(
+                            "couldn't remove subscription for ChatID: '{}': {}",
+                            client.id(),
+                            e
+                        );
+                    }
+                    let notice = format!(
+                        "Stopped listening to {}/{}: {}",
+                        subscribed.subreddit(),
+                        subscribed.category(),
+                        reason
+                    );
+                    if let Err(e) = outbox.send_text(chat, notice).await {
+                        error!("couldn't notify ChatID: '{}': {}", client.id(), e);
+                    }
+                    break;
+                }
+            };
+            // The feed already left out posts it broadcast before, so
+            // a post in the vault was only stored for another chat.
+            let (db_storage, db_pool, stored, listing) = (
+                storage.clone(),
+                pool.clone(),
+                post.clone(),
+                subscribed.clone(),
+            );
+            let (mode, limits) = db::blocking(move || {
+                let mut vault = db_storage.vault();
+                if vault.fetch(stored.id()).is_none() {
+                    vault.save(&stored);
+                }
+                let mode = db_storage.subscriptions().delivery_mode(client, &listing);
+                (mode, DigestQueue::from(&db_pool).limits(client))
+            })
+            .await;
+
+            let realtime = mode == DeliveryMode::Realtime;
+            if !realtime
+                || !throttle
+                    .lock()
+                    .await
+                    .try_acquire(client, &limits, Utc::now())
+            {
+                if realtime {
+                    info!(
+                        "Held back PostID: '{}' for ChatID: '{}'",
+                        post.id(),
+                        client.id()
+                    );
+                }
+                let (db_pool, queued, listing) = (pool.clone(), post.clone(), subscribed.clone());
+                db::blocking(move || {
+                    DigestQueue::from(&db_pool).enqueue(client, &listing, &queued)
+                })
+                .await;
+                continue;
+            }
+
+            let (db_pool, pushed) = (pool.clone(), post.clone());
+            db::blocking(move || DeliveryQueue::from(&db_pool).push(client, &pushed, true)).await;
+            let res = outbox.send_post(chat, &post).await;
+            let (db_pool, sent) = (pool.clone(), post.clone());
+            db::blocking(move || {
+                DeliveryQueue::from(&db_pool).settle(client, &sent, 0, &res, Utc::now())
+            })
+            .await;
+            info!(
+                "Forwarded PostID: '{}' to ChatID: '{}'",
+                post.id(),
+                client.id()
+            );
+        }
+    };
+    spawn(task);
+    true
+}
+
+/// Listens again to every subscription saved before a restart. The feeds
+/// pick up from their saved cursors, skipping posts they already sent.
+pub async fn resume<T: ListingSource>(
+    storage: Arc<dyn Storage>,
+    throttle: Arc<Mutex<DeliveryThrottle>>,
+    outbox: Outbox,
+    curator: &Curator<T>,
+    listeners: &Listeners,
+    pool: DbPool,
+) -> QueryResult<usize> {
+    let db_storage = storage.clone();
+    let subscriptions = db::blocking(move || db_storage.subscriptions().all()).await?;
+    let resumed = subscriptions
+        .into_iter()
+        .filter(|(client, listing)| {
+            listen(
+                ChatId(client.id()),
+                listing.clone(),
+                storage.clone(),
+                throttle.clone(),
+                outbox.clone(),
+                curator,
+                listeners,
+                pool.clone(),
+            )
+        })
+        .count();
+    Ok(resumed)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn listen_silence_handler<T: ListingSource>(
     tg_bot: Bot,
     msg: Message,
-    store: Arc<Mutex<AggregatorStore>>,
-    clients: Arc<Mutex<ClientManager>>,
+    storage: Arc<dyn Storage>,
+    throttle: Arc<Mutex<DeliveryThrottle>>,
+    outbox: Outbox,
+    curator: Curator<T>,
+    listeners: Listeners,
+    pool: DbPool,
+    config: Arc<Config>,
 ) -> ResponseResult<()> {
     let msg = msg.clone();
     let bot = tg_bot.clone();
@@ -99,47 +300,344 @@ pub async fn listen_silence_handler(
                 msg.from().unwrap().id,
                 msg.chat.id
             );
-            let mut guard = store.lock().await;
-            let mut user = guard.find(msg.chat.id.0.into()).unwrap();
+            let client = ClientID::from(msg.chat.id.0);
+            let botclient = BotClient {
+                id: client,
+                username: msg.chat.username().map(String::from),
+                is_user: msg.chat.is_private(),
+            };
 
-            let task = async move {
-                user.attach_curator(Curator::from(Api::from(&Client::new())));
-                user.add_listing(listing);
-
-                while let Some(post) = user.curator.as_mut().unwrap().chan.1.recv().await {
-                    let mut vault = ArtVault::instance();
-                    let is_post = vault.fetch(post.id());
-                    if is_post.is_some() {
-                        continue;
+            let (db_storage, subscribed) = (storage.clone(), listing.clone());
+            let res = db::blocking(move || {
+                let mut cli_mgr = db_storage.clients();
+                if cli_mgr.get(client).is_none() {
+                    cli_mgr.add(botclient);
+                }
+                db_storage.subscriptions().subscribe(client, &subscribed)
+            })
+            .await;
+            if let Err(e) = res {
+                error!(
+                    "couldn't persist subscription for ChatID: '{}': {}",
+                    client.id(),
+                    e
+                );
+            }
+            listen(
+                msg.chat.id,
+                listing,
+                storage,
+                throttle,
+                outbox,
+                &curator,
+                &listeners,
+                pool,
+            );
+        }
+
+        Silence { 0: sub } => {
+            info!(
+                "`/silence` command requested by userid: {}",
+                msg.from().unwrap().id
+            );
+            let client = ClientID::from(msg.chat.id.0);
+            let (db_storage, subreddit) = (storage.clone(), sub.clone());
+            let silenced = db::blocking(move || {
+                let mut subscriptions = db_storage.subscriptions();
+                let silenced = subscriptions
+                    .listings(client)?
+                    .into_iter()
+                    .filter(|listing| listing.subreddit() == subreddit)
+                    .collect::<Vec<_>>();
+                for listing in &silenced {
+                    if let Err(e) = subscriptions.unsubscribe(client, listing) {
+                        error!(
+                            "couldn't remove subscription for ChatID: '{}': {}",
+                            client.id(),
+                            e
+                        );
                     }
+                }
+                Ok::<_, diesel::result::Error>(silenced)
+            })
+            .await;
 
-                    let url = Url::parse(post.media_href.as_str()).unwrap();
-                    let file = InputFile::url(url);
+            let reply = match silenced {
+                Ok(silenced) if silenced.is_empty() => format!("You aren't listening to {}", sub),
+                Ok(_) => {
+                    listeners.stop(client, &sub);
+                    format!("Stopped listening to {}", sub)
+                }
+                Err(e) => {
+                    error!(
+                        "couldn't load subscriptions of ChatID: '{}': {}",
+                        client.id(),
+                        e
+                    );
+                    format!(
+                        "Couldn't look up your subscriptions to {}, try again later",
+                        sub
+                    )
+                }
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
 
-                    if let Ok(_) = bot
-                        .send_photo(msg.chat.id, file)
-                        .caption(format!("<i>{}</i>", post.title()))
-                        .parse_mode(ParseMode::Html)
-                        .await
-                    {
-                        vault.save(&post);
+        Deliver {
+            0: listing,
+            1: mode,
+        } => {
+            info!(
+                "`/deliver` command requested by userid: {}",
+                msg.from().unwrap().id
+            );
+            let client = ClientID::from(msg.chat.id.0);
+            let (db_storage, updated) = (storage.clone(), listing.clone());
+            let updated = db::blocking(move || {
+                db_storage
+                    .subscriptions()
+                    .set_delivery_mode(client, &updated, mode)
+            })
+            .await;
+            let reply = match updated {
+                Ok(true) => format!(
+                    "Posts from {}/{} will be delivered {}",
+                    listing.subreddit(),
+                    listing.category(),
+                    match mode {
+                        DeliveryMode::Realtime => "in real-time".to_string(),
+                        DeliveryMode::Hourly => "as an hourly digest".to_string(),
+                        DeliveryMode::Daily => "as a daily digest".to_string(),
+                        DeliveryMode::Weekly => "as a weekly digest of the best".to_string(),
+                        DeliveryMode::Top(n) => format!("as a daily digest of the top {}", n),
                     }
-                    info!(
-                        "Forwarded PostID: '{}' to UserID: '{}'",
-                        post.id(),
-                        msg.from().unwrap().id
+                ),
+                Ok(false) => format!(
+                    "You aren't listening to {}/{}",
+                    listing.subreddit(),
+                    listing.category()
+                ),
+                Err(e) => {
+                    error!("couldn't update delivery mode: {}", e);
+                    "Couldn't update the delivery mode, try again later".to_string()
+                }
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+
+        Digest { 0: schedule } => {
+            info!(
+                "`/digest` command requested by userid: {}",
+                msg.from().unwrap().id
+            );
+            let client = ClientID::from(msg.chat.id.0);
+            let (db_pool, saved) = (pool.clone(), schedule.clone());
+            let res =
+                db::blocking(move || DigestQueue::from(&db_pool).set_schedule(client, &saved))
+                    .await;
+            let reply = match res {
+                Ok(_) => format!(
+                    "Daily digests will be sent at {:02}:00 ({})",
+                    schedule.hour,
+                    schedule.timezone.name()
+                ),
+                Err(e) => {
+                    error!("couldn't save digest schedule: {}", e);
+                    "Couldn't update your digest schedule, try again later".to_string()
+                }
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+
+        Quiet {
+            0: hours,
+            1: timezone,
+        } => {
+            info!(
+                "`/quiet` command requested by userid: {}",
+                msg.from().unwrap().id
+            );
+            let client = ClientID::from(msg.chat.id.0);
+            let db_pool = pool.clone();
+            let res = db::blocking(move || {
+                DigestQueue::from(&db_pool).set_quiet_hours(client, hours, timezone)
+            })
+            .await;
+            let reply = match (res, hours) {
+                (Ok(_), Some((start, end))) => format!(
+                    "Nothing will be delivered between {:02}:00 and {:02}:00",
+                    start, end
+                ),
+                (Ok(_), None) => "Quiet hours turned off".to_string(),
+                (Err(e), _) => {
+                    error!("couldn't update quiet hours: {}", e);
+                    "Couldn't update your quiet hours, try again later".to_string()
+                }
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+
+        Budget {
+            0: budget,
+            1: overflow,
+        } => {
+            info!(
+                "`/budget` command requested by userid: {}",
+                msg.from().unwrap().id
+            );
+            let client = ClientID::from(msg.chat.id.0);
+            let db_pool = pool.clone();
+            let res = db::blocking(move || {
+                DigestQueue::from(&db_pool).set_budget(client, budget, overflow)
+            })
+            .await;
+            let reply = match (res, budget) {
+                (Ok(_), Some(n)) => format!(
+                    "At most {} post(s) will be delivered per hour, the rest are {}",
+                    n,
+                    match overflow {
+                        OverflowPolicy::Queue => "queued for later",
+                        OverflowPolicy::DropLowest => "dropped, lowest scored first",
+                        OverflowPolicy::Digest => "sent with your next daily digest",
+                    }
+                ),
+                (Ok(_), None) => "Hourly budget turned off".to_string(),
+                (Err(e), _) => {
+                    error!("couldn't update hourly budget: {}", e);
+                    "Couldn't update your hourly budget, try again later".to_string()
+                }
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+
+        Failed => {
+            info!(
+                "`/failed` command requested by userid: {}",
+                msg.from().unwrap().id
+            );
+            let (db_pool, client) = (pool.clone(), ClientID::from(msg.chat.id.0));
+            let failed =
+                db::blocking(move || DeliveryQueue::from(&db_pool).dead_letters(client, 10)).await;
+            let reply = if failed.is_empty() {
+                "Every post has been delivered".to_string()
+            } else {
+                let mut reply = "Posts that couldn't be delivered:".to_string();
+                for (letter, post) in failed {
+                    reply.push_str(
+                        format!(
+                            "\n• {} ({} attempt(s), {}): {}",
+                            post.title(),
+                            letter.attempts,
+                            letter.failed_at.format("%Y-%m-%d %H:%M UTC"),
+                            letter.error
+                        )
+                        .as_str(),
                     );
                 }
+                reply
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+
+        Random { 0: listing } => {
+            info!(
+                "`/random` command requested by userid: {}",
+                msg.from().unwrap().id
+            );
+            let mut listing = listing;
+            let posts = match curator.fetch(&mut listing).await {
+                Ok(posts) => posts,
+                Err(e) => {
+                    warn!(
+                        "couldn't pick a random post from {}: {}",
+                        listing.subreddit(),
+                        e
+                    );
+                    bot.send_message(
+                        msg.chat.id,
+                        format!("Couldn't pick a post from {}: {}", listing.subreddit(), e),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+            };
+            match posts.front() {
+                Some(post) => outbox.send_post(msg.chat.id, post).await?,
+                None => {
+                    bot.send_message(
+                        msg.chat.id,
+                        format!("{} has no posts to pick from", listing.subreddit()),
+                    )
+                    .await?;
+                }
+            }
+        }
+
+        Backfill { 0: job } => {
+            info!(
+                "`/backfill` command requested by userid: {}",
+                msg.from().unwrap().id
+            );
+            let listing = format!("{}/{}", job.listing().subreddit(), job.listing().category());
+            let task = async move {
+                let reply = match job.run(curator, pool).await {
+                    Ok(0) => format!("Found no posts in {} you haven't got already", listing),
+                    Ok(n) => format!(
+                        "Found {} older post(s) in {}, they'll trickle in over time",
+                        n, listing
+                    ),
+                    Err(e) => {
+                        warn!("couldn't backfill {}: {}", listing, e);
+                        format!("Couldn't look back through {}: {}", listing, e)
+                    }
+                };
+                if let Err(e) = bot.send_message(msg.chat.id, reply).await {
+                    error!("couldn't notify ChatID: '{}': {}", msg.chat.id, e);
+                }
             };
             spawn(task);
         }
 
-        Silence { 0: sub } => {
+        Polling => {
             info!(
-                "`/silence` command requested by userid: {}",
+                "`/polling` command requested by userid: {}",
                 msg.from().unwrap().id
             );
-            // curator.detach_listeners(&sub);
+            if !config.is_admin(msg.from().unwrap().id.0) {
+                bot.send_message(msg.chat.id, "Only admins can see how listings are polled")
+                    .await?;
+                return Ok(());
+            }
+            let polling = curator.polling();
+            let reply = if polling.is_empty() {
+                "No listing is being polled".to_string()
+            } else {
+                let mut reply = "Listings are polled every:".to_string();
+                for status in polling {
+                    let gap = match status.mean_gap {
+                        Some(gap) => format!(", a post every ~{}s", gap.as_secs()),
+                        None => String::new(),
+                    };
+                    let health = match (&status.health.health, status.health.restarts) {
+                        (Health::Running, 0) => String::new(),
+                        (Health::Running, n) => format!(" (restarted {} time(s))", n),
+                        (Health::Restarting { error }, _) => format!(" (crashed: {})", error),
+                        (Health::Stopped, _) => " (stopped)".to_string(),
+                    };
+                    reply.push_str(
+                        format!(
+                            "\n• {}: {}s{}{}",
+                            status.listing,
+                            status.interval.as_secs(),
+                            gap,
+                            health
+                        )
+                        .as_str(),
+                    );
+                }
+                reply
+            };
+            bot.send_message(msg.chat.id, reply).await?;
         }
     }
 
@@ -152,6 +650,14 @@ struct ArgumentError;
 enum Command {
     Listen(Listing),
     Silence(Subreddit),
+    Deliver(Listing, DeliveryMode),
+    Digest(DigestSchedule),
+    Quiet(Option<(u32, u32)>, Option<Tz>),
+    Budget(Option<u32>, OverflowPolicy),
+    Failed,
+    Random(Listing),
+    Backfill(backfill::Backfill),
+    Polling,
 }
 
 impl Command {
@@ -164,9 +670,12 @@ impl Command {
         let cmd = values.first().unwrap();
         match *cmd {
             "/listen" => {
-                if let Some(sub) = values.get(1) {
-                    if let Some(listing) = values.get(2) {
-                        let listing = Listing::from(listing, Subreddit::from(sub));
+                if let (Some(sub), Some(category)) = (values.get(1), values.get(2)) {
+                    let category = match values.get(3) {
+                        Some(window) => format!("{}/{}", category, window),
+                        None => category.to_string(),
+                    };
+                    if let Some(listing) = Listing::parse(&category, Subreddit::from(*sub)) {
                         return Ok(Listen(listing));
                     }
                 }
@@ -174,10 +683,85 @@ impl Command {
             }
             "/silence" => {
                 if let Some(sub) = values.get(1) {
-                    return Ok(Silence(Subreddit::from(sub)));
+                    return Ok(Silence(Subreddit::from(*sub)));
                 }
                 Err(ArgumentError)
             }
+            "/deliver" => {
+                if let (Some(sub), Some(listing), Some(mode)) =
+                    (values.get(1), values.get(2), values.get(3))
+                {
+                    let size = values.get(4).and_then(|n| n.parse().ok());
+                    if let (Some(mode), Some(listing)) = (
+                        DeliveryMode::from(mode, size),
+                        Listing::parse(listing, Subreddit::from(*sub)),
+                    ) {
+                        return Ok(Deliver(listing, mode));
+                    }
+                }
+                Err(ArgumentError)
+            }
+            "/digest" => {
+                if let Some(hour) = values.get(1) {
+                    if let Some(schedule) = DigestSchedule::from(hour, values.get(2).copied()) {
+                        return Ok(Digest(schedule));
+                    }
+                }
+                Err(ArgumentError)
+            }
+            "/quiet" => match (values.get(1), values.get(2)) {
+                (Some(&"off"), None) => Ok(Quiet(None, None)),
+                (Some(start), Some(end)) => {
+                    let hour = |h: &str| h.parse::<u32>().ok().filter(|h| *h < 24);
+                    let timezone = match values.get(3) {
+                        Some(name) => Some(name.parse::<Tz>().map_err(|_| ArgumentError)?),
+                        None => None,
+                    };
+                    match (hour(start), hour(end)) {
+                        // Quiet hours starting and ending together would never apply.
+                        (Some(start), Some(end)) if start != end => {
+                            Ok(Quiet(Some((start, end)), timezone))
+                        }
+                        _ => Err(ArgumentError),
+                    }
+                }
+                _ => Err(ArgumentError),
+            },
+            "/failed" => Ok(Failed),
+            "/polling" => Ok(Polling),
+            "/random" => match values.get(1) {
+                Some(sub) => Listing::parse("random", Subreddit::from(*sub))
+                    .map(Random)
+                    .ok_or(ArgumentError),
+                None => Err(ArgumentError),
+            },
+            "/backfill" => {
+                if let (Some(sub), Some(category)) = (values.get(1), values.get(2)) {
+                    let listing = Listing::parse(category, Subreddit::from(*sub));
+                    let limit = BackfillLimit::from(values.get(3).copied());
+                    if let (Some(listing), Some(limit)) = (listing, limit) {
+                        let chat = ClientID::from(msg.chat.id.0);
+                        return backfill::Backfill::from(chat, listing, limit)
+                            .map(Backfill)
+                            .ok_or(ArgumentError);
+                    }
+                }
+                Err(ArgumentError)
+            }
+            "/budget" => {
+                let overflow = match values.get(2) {
+                    Some(policy) => OverflowPolicy::from(policy).ok_or(ArgumentError)?,
+                    None => OverflowPolicy::Queue,
+                };
+                match values.get(1) {
+                    Some(&"off") => Ok(Budget(None, overflow)),
+                    Some(n) => match n.parse::<u32>() {
+                        Ok(n) if n > 0 => Ok(Budget(Some(n), overflow)),
+                        _ => Err(ArgumentError),
+                    },
+                    None => Err(ArgumentError),
+                }
+            }
             _ => Err(ArgumentError),
         }
     }
@@ -188,6 +772,242 @@ impl ToString for Command {
         match self {
             Listen { .. } => "/listen".to_string(),
             Silence { .. } => "/silence".to_string(),
+            Deliver { .. } => "/deliver".to_string(),
+            Digest { .. } => "/digest".to_string(),
+            Quiet { .. } => "/quiet".to_string(),
+            Budget { .. } => "/budget".to_string(),
+            Failed => "/failed".to_string(),
+            Random { .. } => "/random".to_string(),
+            Backfill { .. } => "/backfill".to_string(),
+            Polling => "/polling".to_string(),
         }
     }
 }
+
+#[tokio::test]
+async fn test_start_registers_client() {
+    use crate::testing::{message, FakeTelegram, MemoryStorage};
+
+    let telegram = FakeTelegram::start();
+    let storage = MemoryStorage::default();
+    let start = || {
+        configuration_cmd_handler(
+            telegram.bot(),
+            message(42, 42, "/start"),
+            ConfCommand::Start,
+            Arc::new(storage.clone()),
+        )
+    };
+
+    start().await.unwrap();
+    let client = storage.client(ClientID::from(42)).unwrap();
+    assert_eq!(client.username.as_deref(), Some("tester"));
+    assert!(client.is_user);
+    assert!(telegram.calls().is_empty());
+
+    start().await.unwrap();
+    let welcome = telegram.wait_for("sendMessage").await;
+    assert_eq!(welcome.param("chat_id"), Some("42"));
+    assert_eq!(welcome.param("text"), Some("Welcome back, tester!"));
+}
+
+#[tokio::test]
+async fn test_listen_delivers_posts() {
+    use crate::outbound::SendLimits;
+    use crate::testing::{message, post, FakeTelegram, MockSource, TestDb};
+
+    let telegram = FakeTelegram::start();
+    let db = TestDb::new();
+    let storage: Arc<dyn Storage> = Arc::new(db.pool.clone());
+    let src = MockSource::default();
+    src.push_page("Art", vec![post("a")]);
+    let curator = Curator::from(src, &db.pool);
+
+    listen_silence_handler(
+        telegram.bot(),
+        message(-42, 7, "/listen Art new"),
+        storage.clone(),
+        Arc::new(Mutex::new(DeliveryThrottle::default())),
+        Outbox::spawn(telegram.bot(), storage.vault(), SendLimits::default()),
+        curator.clone(),
+        Listeners::default(),
+        db.pool.clone(),
+        Arc::new(Config::default()),
+    )
+    .await
+    .unwrap();
+
+    let sent = telegram.wait_for("sendPhoto").await;
+    assert_eq!(sent.param("chat_id"), Some("-42"));
+    assert_eq!(sent.param("photo"), Some("https://i.redd.it/a.png"));
+    let listings = storage
+        .subscriptions()
+        .listings(ClientID::from(-42))
+        .unwrap();
+    assert_eq!(listings, vec![Listing::parse("new", "Art".into()).unwrap()]);
+    assert!(storage.vault().fetch("a").is_some());
+
+    curator.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_silence_stops_feed() {
+    use std::time::Duration;
+
+    use crate::outbound::SendLimits;
+    use crate::testing::{message, post, FakeTelegram, MockSource, TestDb};
+
+    let telegram = FakeTelegram::start();
+    let db = TestDb::new();
+    let storage: Arc<dyn Storage> = Arc::new(db.pool.clone());
+    let src = MockSource::default();
+    src.push_page("Art", vec![post("a")]);
+    let curator = Curator::from(src, &db.pool);
+    let (throttle, outbox, listeners, config) = (
+        Arc::new(Mutex::new(DeliveryThrottle::default())),
+        Outbox::spawn(telegram.bot(), storage.vault(), SendLimits::default()),
+        Listeners::default(),
+        Arc::new(Config::default()),
+    );
+    let command = |chat: i64, text: &str| {
+        listen_silence_handler(
+            telegram.bot(),
+            message(chat, 7, text),
+            storage.clone(),
+            throttle.clone(),
+            outbox.clone(),
+            curator.clone(),
+            listeners.clone(),
+            db.pool.clone(),
+            config.clone(),
+        )
+    };
+    // Every chat listening to a listing gets its posts.
+    command(-42, "/listen Art new").await.unwrap();
+    command(-43, "/listen Art new").await.unwrap();
+    let mut chats = telegram
+        .wait_for_calls("sendPhoto", 2)
+        .await
+        .into_iter()
+        .filter_map(|call| call.param("chat_id").map(String::from))
+        .collect::<Vec<_>>();
+    chats.sort();
+    assert_eq!(chats, vec!["-42", "-43"]);
+
+    // The feed keeps going for the chat still listening, and stops after
+    // the last one is silenced.
+    command(-42, "/silence Art").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(curator.polling().len(), 1);
+    assert!(storage
+        .subscriptions()
+        .listings(ClientID::from(-42))
+        .unwrap()
+        .is_empty());
+
+    command(-43, "/silence Art").await.unwrap();
+    for _ in 0..500 {
+        if curator.polling().is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(curator.polling().is_empty());
+    assert!(storage
+        .subscriptions()
+        .listings(ClientID::from(-43))
+        .unwrap()
+        .is_empty());
+
+    curator.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_resume_skips_sent_posts() {
+    use std::time::Duration;
+
+    use crate::outbound::SendLimits;
+    use crate::testing::{post, FakeTelegram, MockSource, TestDb};
+
+    let telegram = FakeTelegram::start();
+    let db = TestDb::new();
+    let storage: Arc<dyn Storage> = Arc::new(db.pool.clone());
+    let outbox = Outbox::spawn(telegram.bot(), storage.vault(), SendLimits::default());
+    let throttle = Arc::new(Mutex::new(DeliveryThrottle::default()));
+    let (client, listing) = (
+        ClientID::from(-42),
+        Listing::parse("new", "Art".into()).unwrap(),
+    );
+    storage.clients().add(BotClient {
+        id: client,
+        username: None,
+        is_user: false,
+    });
+    storage.subscriptions().subscribe(client, &listing).unwrap();
+
+    // The second run restarts on the same database, and Reddit still lists
+    // the post sent before the restart.
+    for (sent, page) in [vec![post("a")], vec![post("a"), post("b")]]
+        .into_iter()
+        .enumerate()
+    {
+        let src = MockSource::default();
+        src.push_page("Art", page);
+        let curator = Curator::from(src, &db.pool);
+        let resumed = resume(
+            storage.clone(),
+            throttle.clone(),
+            outbox.clone(),
+            &curator,
+            &Listeners::default(),
+            db.pool.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resumed, 1);
+
+        telegram.wait_for_calls("sendPhoto", sent + 1).await;
+        curator.shutdown().await;
+    }
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let photos = telegram
+        .calls()
+        .into_iter()
+        .filter(|call| call.method.eq_ignore_ascii_case("sendPhoto"))
+        .filter_map(|call| call.param("photo").map(String::from))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        photos,
+        vec!["https://i.redd.it/a.png", "https://i.redd.it/b.png"]
+    );
+}
+
+#[test]
+fn test_quiet_hours_command() {
+    use crate::testing::message;
+
+    let parse = |text: &str| Command::parse(&message(42, 42, text));
+    assert!(matches!(
+        parse("/quiet 22 7"),
+        Ok(Quiet(Some((22, 7)), None))
+    ));
+    assert!(matches!(parse("/quiet off"), Ok(Quiet(None, None))));
+    assert!(parse("/quiet 5 5").is_err());
+    assert!(parse("/quiet 5 24").is_err());
+}
+
+#[test]
+fn test_subscribe_commands() {
+    use teloxide::utils::command::BotCommands;
+
+    // Arguments are left for `Command::parse`.
+    assert!(matches!(
+        SubscribeCommand::parse("/listen Art top week", "artbutler"),
+        Ok(SubscribeCommand::Listen)
+    ));
+    assert!(matches!(
+        SubscribeCommand::parse("/polling", "artbutler"),
+        Ok(SubscribeCommand::Polling)
+    ));
+}
@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use diesel::QueryResult;
+
+use crate::aggregator::AggregatorStore;
+use crate::artvault::ArtVault;
+use crate::auth::{BotClient, ClientID, ClientManager};
+use crate::content::Post;
+use crate::db::Storage;
+use crate::delivery::DeliveryMode;
+use crate::listings::reddit::Listing;
+
+/// Subscriptions are keyed like the `subscribed_listings` table.
+type SubscriptionKey = (i64, String, String);
+
+#[derive(Default)]
+struct State {
+    posts: HashMap<String, Post>,
+    file_ids: HashMap<String, String>,
+    clients: HashMap<i64, BotClient>,
+    subscriptions: HashMap<SubscriptionKey, DeliveryMode>,
+}
+
+/// A `Storage` kept in memory. Clones share the same state, so a test can
+/// hand one to the code under test and inspect it afterwards.
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    state: Arc<Mutex<State>>,
+}
+
+impl MemoryStorage {
+    pub fn posts(&self) -> Vec<Post> {
+        self.state.lock().unwrap().posts.values().cloned().collect()
+    }
+
+    pub fn client(&self, client: ClientID) -> Option<BotClient> {
+        self.state
+            .lock()
+            .unwrap()
+            .clients
+            .get(&client.id())
+            .cloned()
+    }
+}
+
+fn key(client: ClientID, listing: &Listing) -> SubscriptionKey {
+    (client.id(), listing.subreddit().name(), listing.category())
+}
+
+impl Storage for MemoryStorage {
+    fn vault(&self) -> Box<dyn ArtVault> {
+        Box::new(self.clone())
+    }
+
+    fn clients(&self) -> Box<dyn ClientManager> {
+        Box::new(MemoryClientManager {
+            storage: self.clone(),
+            existing: vec![],
+        })
+    }
+
+    fn subscriptions(&self) -> Box<dyn AggregatorStore> {
+        Box::new(self.clone())
+    }
+}
+
+impl ArtVault for MemoryStorage {
+    fn save(&mut self, p: &Post) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .posts
+            .entry(p.id().clone())
+            .or_insert_with(|| p.clone());
+    }
+
+    fn fetch(&mut self, post_id: &str) -> Option<Post> {
+        self.state.lock().unwrap().posts.get(post_id).cloned()
+    }
+
+    fn file_id(&mut self, href: &str) -> Option<String> {
+        self.state.lock().unwrap().file_ids.get(href).cloned()
+    }
+
+    fn save_file_id(&mut self, href: &str, file_id: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.file_ids.insert(href.to_string(), file_id.to_string());
+    }
+
+    fn forget_file_id(&mut self, href: &str) {
+        self.state.lock().unwrap().file_ids.remove(href);
+    }
+}
+
+impl AggregatorStore for MemoryStorage {
+    fn listings(&mut self, client: ClientID) -> QueryResult<Vec<Listing>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .subscriptions
+            .keys()
+            .filter(|(user, _, _)| *user == client.id())
+            .filter_map(|(_, sub, category)| Listing::parse(category, sub.as_str().into()))
+            .collect())
+    }
+
+    fn all(&mut self) -> QueryResult<Vec<(ClientID, Listing)>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .subscriptions
+            .keys()
+            .filter_map(|(user, sub, category)| {
+                Some((
+                    (*user).into(),
+                    Listing::parse(category, sub.as_str().into())?,
+                ))
+            })
+            .collect())
+    }
+
+    fn subscribe(&mut self, client: ClientID, listing: &Listing) -> QueryResult<usize> {
+        let mut state = self.state.lock().unwrap();
+        let subscriptions = &mut state.subscriptions;
+        if subscriptions.contains_key(&key(client, listing)) {
+            return Ok(0);
+        }
+        subscriptions.insert(key(client, listing), DeliveryMode::Realtime);
+        Ok(1)
+    }
+
+    fn unsubscribe(&mut self, client: ClientID, listing: &Listing) -> QueryResult<usize> {
+        let mut state = self.state.lock().unwrap();
+        Ok(state
+            .subscriptions
+            .remove(&key(client, listing))
+            .map_or(0, |_| 1))
+    }
+
+    fn set_delivery_mode(
+        &mut self,
+        client: ClientID,
+        listing: &Listing,
+        mode: DeliveryMode,
+    ) -> QueryResult<bool> {
+        let mut state = self.state.lock().unwrap();
+        match state.subscriptions.get_mut(&key(client, listing)) {
+            Some(current) => {
+                *current = mode;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn delivery_mode(&mut self, client: ClientID, listing: &Listing) -> DeliveryMode {
+        let state = self.state.lock().unwrap();
+        state
+            .subscriptions
+            .get(&key(client, listing))
+            .copied()
+            .unwrap_or(DeliveryMode::Realtime)
+    }
+}
+
+/// Hands out references into `existing`, like `SqlClientManager`, since the
+/// shared state can't be borrowed past its lock.
+struct MemoryClientManager {
+    storage: MemoryStorage,
+    existing: Vec<BotClient>,
+}
+
+impl ClientManager for MemoryClientManager {
+    fn get(&mut self, user: ClientID) -> Option<&BotClient> {
+        let client = self.storage.client(user)?;
+        self.existing.push(client);
+        self.existing.last()
+    }
+
+    fn add(&mut self, new_user: BotClient) {
+        let mut state = self.storage.state.lock().unwrap();
+        state.clients.entry(new_user.id.id()).or_insert(new_user);
+    }
+}
+
+#[test]
+fn test_memory_storage() {
+    use crate::testing::post;
+
+    let storage = MemoryStorage::default();
+    let mut vault = storage.vault();
+    vault.save(&post("a"));
+    vault.save(&post("a").posted(chrono::Utc::now()));
+    assert_eq!(vault.fetch("a"), Some(post("a")));
+    assert_eq!(storage.posts(), vec![post("a")]);
+    vault.save_file_id("https://i.redd.it/a.png", "file");
+    assert_eq!(
+        storage.vault().file_id("https://i.redd.it/a.png"),
+        Some("file".to_string())
+    );
+
+    let client = ClientID::from(7);
+    let listing = Listing::parse("top/week", "Art".into()).unwrap();
+    let mut subscriptions = storage.subscriptions();
+    assert!(!subscriptions
+        .set_delivery_mode(client, &listing, DeliveryMode::Daily)
+        .unwrap());
+    assert_eq!(subscriptions.subscribe(client, &listing).unwrap(), 1);
+    assert_eq!(subscriptions.subscribe(client, &listing).unwrap(), 0);
+    assert!(subscriptions
+        .set_delivery_mode(client, &listing, DeliveryMode::Daily)
+        .unwrap());
+    assert_eq!(
+        storage.subscriptions().delivery_mode(client, &listing),
+        DeliveryMode::Daily
+    );
+    assert_eq!(
+        storage.subscriptions().listings(client).unwrap(),
+        vec![listing.clone()]
+    );
+    assert_eq!(subscriptions.unsubscribe(client, &listing).unwrap(), 1);
+    assert!(subscriptions.listings(client).unwrap().is_empty());
+}
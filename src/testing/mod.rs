@@ -0,0 +1,57 @@
+//! Doubles for running handlers, curators and repositories offline: an
+//! in-memory `Storage`, a scripted `ListingSource`, a fake Telegram Bot API
+//! and a throwaway SQLite database for what has to be SQL.
+
+mod memory;
+mod source;
+mod telegram;
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::content::Post;
+use crate::db::{self, DbPool};
+
+pub use memory::MemoryStorage;
+pub use source::MockSource;
+pub use telegram::{message, FakeTelegram};
+
+/// A migrated SQLite database in a temporary file, removed once dropped.
+pub struct TestDb {
+    pub pool: DbPool,
+    path: PathBuf,
+}
+
+impl TestDb {
+    pub fn new() -> Self {
+        static CREATED: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "artbutler-{}-{}.db",
+            std::process::id(),
+            CREATED.fetch_add(1, Ordering::Relaxed)
+        ));
+        let pool = db::connect(&format!("sqlite://{}", path.display()));
+        db::migrate(&pool).expect("couldn't migrate the test database");
+        Self { pool, path }
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", self.path.display(), suffix));
+        }
+    }
+}
+
+/// A post with an image that only differs from others by `id`.
+pub fn post(id: &str) -> Post {
+    Post::new(
+        id.to_string(),
+        format!("https://i.redd.it/{}.png", id),
+        "author".to_string(),
+        format!("post {}", id),
+        (1, 0),
+    )
+}
@@ -0,0 +1,71 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+
+use crate::content::Post;
+use crate::listings::reddit::Listing;
+use crate::listings::source::{ListingSource, SourceError};
+
+#[derive(Default)]
+struct Script {
+    pages: HashMap<String, VecDeque<Vec<Post>>>,
+    gone: HashSet<String>,
+    requests: usize,
+}
+
+/// A `ListingSource` answering with the pages queued for each subreddit,
+/// one per request, and with no posts once they ran out. Clones share the
+/// same script.
+#[derive(Clone, Default)]
+pub struct MockSource {
+    script: Arc<Mutex<Script>>,
+}
+
+impl MockSource {
+    /// Queues the posts the next request for `subreddit` returns.
+    pub fn push_page(&self, subreddit: &str, posts: Vec<Post>) {
+        let mut script = self.script.lock().unwrap();
+        script
+            .pages
+            .entry(subreddit.to_string())
+            .or_default()
+            .push_back(posts);
+    }
+
+    /// Makes requests for `subreddit` fail as if it was banned.
+    pub fn remove(&self, subreddit: &str) {
+        let mut script = self.script.lock().unwrap();
+        script.gone.insert(subreddit.to_string());
+    }
+
+    /// How many times posts were retrieved, across every listing.
+    pub fn requests(&self) -> usize {
+        self.script.lock().unwrap().requests
+    }
+}
+
+#[async_trait]
+impl ListingSource for MockSource {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    async fn retrieve_posts(
+        &mut self,
+        listing: &mut Listing,
+    ) -> Result<VecDeque<Post>, SourceError> {
+        let sub = listing.subreddit().name();
+        let mut script = self.script.lock().unwrap();
+        script.requests += 1;
+        if script.gone.contains(&sub) {
+            return Err(SourceError::NotFound);
+        }
+        let page = script
+            .pages
+            .get_mut(&sub)
+            .and_then(|pages| pages.pop_front())
+            .unwrap_or_default();
+        Ok(page.into())
+    }
+}
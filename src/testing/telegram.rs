@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Request, Response, Server};
+use serde_json::{json, Value};
+use teloxide::types::Message;
+use teloxide::Bot;
+use tokio::time::{sleep, Instant};
+
+/// How long `wait_for` waits for the bot to call a method.
+const CALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A Bot API method the bot called, with its parameters as sent.
+#[derive(Debug, Clone)]
+pub struct ApiCall {
+    pub method: String,
+    pub params: HashMap<String, String>,
+}
+
+impl ApiCall {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+}
+
+/// A local stand-in for the Telegram Bot API that records every call and
+/// answers as if it succeeded.
+pub struct FakeTelegram {
+    addr: SocketAddr,
+    calls: Arc<Mutex<Vec<ApiCall>>>,
+}
+
+impl FakeTelegram {
+    /// Starts serving on a free local port, on the current runtime.
+    pub fn start() -> Self {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let recorded = calls.clone();
+        let make_service = make_service_fn(move |_| {
+            let recorded = recorded.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| answer(req, recorded.clone()))) }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        Self { addr, calls }
+    }
+
+    /// A bot sending its requests here.
+    pub fn bot(&self) -> Bot {
+        let url = format!("http://{}/", self.addr);
+        Bot::new("123456:fake").set_api_url(url.parse().unwrap())
+    }
+
+    pub fn calls(&self) -> Vec<ApiCall> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Waits for the first call to `method`, e.g. `sendPhoto`.
+    pub async fn wait_for(&self, method: &str) -> ApiCall {
+        self.wait_for_calls(method, 1).await.remove(0)
+    }
+
+    /// Waits until `method` was called `count` times, returning those calls.
+    pub async fn wait_for_calls(&self, method: &str, count: usize) -> Vec<ApiCall> {
+        let deadline = Instant::now() + CALL_TIMEOUT;
+        loop {
+            let calls = self
+                .calls()
+                .into_iter()
+                .filter(|call| call.method.eq_ignore_ascii_case(method))
+                .take(count)
+                .collect::<Vec<_>>();
+            if calls.len() == count {
+                return calls;
+            }
+            if Instant::now() > deadline {
+                panic!(
+                    "`{}` wasn't called {} time(s), got {:?}",
+                    method,
+                    count,
+                    self.calls()
+                );
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
+
+async fn answer(
+    req: Request<Body>,
+    calls: Arc<Mutex<Vec<ApiCall>>>,
+) -> Result<Response<Body>, Infallible> {
+    let method = req
+        .uri()
+        .path()
+        .rsplit('/')
+        .next()
+        .unwrap_or("")
+        .to_string();
+    let content_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+    let body = hyper::body::to_bytes(req.into_body())
+        .await
+        .unwrap_or_default();
+    let params = match content_type.split_once("boundary=") {
+        Some((_, boundary)) => form_params(&body, boundary),
+        None => json_params(&body),
+    };
+
+    let chat = params
+        .get("chat_id")
+        .and_then(|id| id.parse().ok())
+        .unwrap_or(0);
+    let mut calls = calls.lock().unwrap();
+    let sent = calls.len() as i64 + 1;
+    let result = match method.to_ascii_lowercase().as_str() {
+        "sendmessage" => sent_message(sent, chat, json!({ "text": params.get("text") })),
+        "sendphoto" | "senddocument" => {
+            let photo = json!([{
+                "file_id": format!("file-{}", sent),
+                "file_unique_id": format!("unique-{}", sent),
+                "width": 1,
+                "height": 1,
+            }]);
+            sent_message(sent, chat, json!({ "photo": photo }))
+        }
+        "sendmediagroup" => json!([]),
+        _ => json!(true),
+    };
+    calls.push(ApiCall { method, params });
+
+    let body = json!({ "ok": true, "result": result }).to_string();
+    Ok(Response::new(Body::from(body)))
+}
+
+fn json_params(body: &[u8]) -> HashMap<String, String> {
+    let params = serde_json::from_slice::<HashMap<String, Value>>(body).unwrap_or_default();
+    params
+        .into_iter()
+        .map(|(name, value)| match value {
+            Value::String(text) => (name, text),
+            value => (name, value.to_string()),
+        })
+        .collect()
+}
+
+/// Reads the text of each part of a multipart/form-data body, enough for
+/// the parameters teloxide sends that way.
+fn form_params(body: &[u8], boundary: &str) -> HashMap<String, String> {
+    let body = String::from_utf8_lossy(body);
+    body.split(&format!("--{}", boundary))
+        .filter_map(|part| {
+            let (headers, value) = part.split_once("\r\n\r\n")?;
+            let name = headers.split("name=\"").nth(1)?.split('"').next()?;
+            Some((name.to_string(), value.trim_end_matches("\r\n").to_string()))
+        })
+        .collect()
+}
+
+fn chat(id: i64) -> Value {
+    if id < 0 {
+        json!({ "id": id, "type": "group", "title": "Test group" })
+    } else {
+        json!({ "id": id, "type": "private", "first_name": "Test", "username": "tester" })
+    }
+}
+
+/// A message the bot sent to `chat`, with `content` like its text or photo.
+fn sent_message(id: i64, chat_id: i64, content: Value) -> Value {
+    let mut message = json!({
+        "message_id": id,
+        "date": 0,
+        "chat": chat(chat_id),
+        "from": { "id": 123456, "is_bot": true, "first_name": "artbutler" },
+    });
+    message
+        .as_object_mut()
+        .unwrap()
+        .extend(content.as_object().unwrap().clone());
+    message
+}
+
+/// A message `user` sent in `chat`, as the bot would receive it.
+pub fn message(chat_id: i64, user: u64, text: &str) -> Message {
+    serde_json::from_value(json!({
+        "message_id": 1,
+        "date": 0,
+        "chat": chat(chat_id),
+        "from": { "id": user, "is_bot": false, "first_name": "Test", "username": "tester" },
+        "text": text,
+    }))
+    .unwrap()
+}